@@ -0,0 +1,40 @@
+//! Conventions shared by moodriver and `trace_test!`, so running traces with `--dir` and
+//! generating tests from them always pick the same files.
+
+use std::path::Path;
+
+/// Directories with this name hold files referenced by traces, and are never run as traces.
+pub const FIXTURES_DIR: &str = "fixtures";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Json,
+    Json5,
+    Yaml,
+    Toml,
+}
+
+/// Every file extension recognised as a trace, along with the format used to parse it.
+/// Extensions are compared case-insensitively.
+pub const TRACE_EXTENSIONS: &[(&str, TraceFormat)] = &[
+    ("json", TraceFormat::Json),
+    ("jsonc", TraceFormat::Json),
+    ("json5", TraceFormat::Json5),
+    ("yaml", TraceFormat::Yaml),
+    ("yml", TraceFormat::Yaml),
+    ("toml", TraceFormat::Toml),
+];
+
+impl TraceFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?;
+        TRACE_EXTENSIONS
+            .iter()
+            .find(|(e, _)| e.eq_ignore_ascii_case(ext))
+            .map(|(_, format)| *format)
+    }
+}
+
+pub fn is_trace_file(path: &Path) -> bool {
+    TraceFormat::from_path(path).is_some()
+}
@@ -0,0 +1,116 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use moodriver_common::{FIXTURES_DIR, is_trace_file};
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{
+    LitStr, Token,
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+};
+
+struct TraceTestInput {
+    manifest: LitStr,
+    dir: LitStr,
+}
+
+impl Parse for TraceTestInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let manifest = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let dir = input.parse()?;
+        input.parse::<Option<Token![,]>>()?;
+        Ok(Self { manifest, dir })
+    }
+}
+
+fn collect_traces(dir: &Path, traces: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if path.file_name().is_some_and(|n| n != FIXTURES_DIR) {
+                collect_traces(&path, traces)?;
+            }
+        } else if is_trace_file(&path) {
+            traces.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn test_name(dir: &Path, trace: &Path, used: &mut HashSet<String>) -> String {
+    let relative = trace.strip_prefix(dir).unwrap_or(trace).with_extension("");
+    let mut name: String = relative
+        .to_string_lossy()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert_str(0, "trace_");
+    }
+
+    let base = name.clone();
+    let mut suffix = 2;
+    while !used.insert(name.clone()) {
+        name = format!("{}_{}", base, suffix);
+        suffix += 1;
+    }
+    name
+}
+
+/// Generates one `#[test]` per trace file in a directory.
+///
+/// ```ignore
+/// moodriver::trace_test!("extension/manifest.json", "traces");
+/// ```
+///
+/// Both paths are relative to the crate root. Trace files in `fixtures` directories are
+/// skipped. Each test runs every case of its trace and fails with the report of the
+/// cases that did not pass.
+#[proc_macro]
+pub fn trace_test(input: TokenStream) -> TokenStream {
+    let TraceTestInput { manifest, dir } = parse_macro_input!(input as TraceTestInput);
+
+    let root = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default());
+    let manifest_path = root.join(manifest.value());
+    let trace_dir = root.join(dir.value());
+
+    let mut traces = Vec::new();
+    if let Err(e) = collect_traces(&trace_dir, &mut traces) {
+        return syn::Error::new(
+            dir.span(),
+            format!("Failed to read traces from {}: {}", trace_dir.display(), e),
+        )
+        .to_compile_error()
+        .into();
+    }
+    traces.sort();
+
+    let manifest_path = manifest_path.to_string_lossy().to_string();
+    let mut used = HashSet::new();
+    let tests = traces.iter().map(|trace| {
+        let ident = format_ident!("{}", test_name(&trace_dir, trace, &mut used));
+        let trace = LitStr::new(&trace.to_string_lossy(), Span::call_site());
+        quote! {
+            #[test]
+            fn #ident() {
+                // Rebuild the test when the trace changes
+                const _: &[u8] = include_bytes!(#trace);
+                ::moodriver::__private::run_trace_test(#manifest_path, #trace);
+            }
+        }
+    });
+
+    quote! { #(#tests)* }.into()
+}
@@ -0,0 +1,544 @@
+use std::fmt;
+
+use serde::Deserialize;
+use serde_json::Value;
+use types::errors::{MoosyncError, Result};
+
+use crate::{CommandReport, CommandStatus, HostCall, matcher::RequestMatcher};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCall {
+    call: String,
+    #[serde(rename = "match")]
+    matcher: Option<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPattern {
+    Kind(String),
+    Call(RawCall),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawCount {
+    Exact(usize),
+    Range {
+        min: Option<usize>,
+        max: Option<usize>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCountAssertion {
+    call: String,
+    #[serde(rename = "match")]
+    matcher: Option<Value>,
+    during: Option<String>,
+    count: Option<RawCount>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawForbidAssertion {
+    forbid: String,
+    #[serde(rename = "match")]
+    matcher: Option<Value>,
+    during: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawOrderAssertion {
+    order: Vec<RawPattern>,
+    during: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawAssertion {
+    Count(RawCountAssertion),
+    Forbid(RawForbidAssertion),
+    Order(RawOrderAssertion),
+}
+
+/// A host request type, optionally restricted to payloads satisfying a `match`.
+struct CallPattern {
+    kind: String,
+    matcher: Option<(RequestMatcher, Value)>,
+}
+
+impl CallPattern {
+    fn new(kind: String, matcher: Option<Value>) -> Result<Self> {
+        let matcher = matcher
+            .map(|m| RequestMatcher::from_value(m.clone()).map(|matcher| (matcher, m)))
+            .transpose()?;
+        Ok(Self { kind, matcher })
+    }
+
+    fn matches(&self, call: &HostCall) -> bool {
+        call.kind == self.kind
+            && self
+                .matcher
+                .as_ref()
+                .is_none_or(|(matcher, _)| matcher.matches(&call.data))
+    }
+}
+
+impl fmt::Display for CallPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.matcher {
+            Some((_, raw)) => write!(f, "{} matching {}", self.kind, raw),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+/// Number of calls allowed by a count assertion. At least one if unset.
+struct Count {
+    min: usize,
+    max: Option<usize>,
+}
+
+impl Count {
+    fn contains(&self, count: usize) -> bool {
+        count >= self.min && self.max.is_none_or(|max| count <= max)
+    }
+}
+
+impl fmt::Display for Count {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "exactly {}", max),
+            Some(max) => write!(f, "between {} and {}", self.min, max),
+            None => write!(f, "at least {}", self.min),
+        }
+    }
+}
+
+enum Rule {
+    Count(CallPattern, Count),
+    Forbid(CallPattern),
+    Order(Vec<CallPattern>),
+}
+
+/// An entry of the `hostCallAssertions` of a trace, checked against every host request
+/// the extension made during a run.
+pub(crate) struct HostCallAssertion {
+    rule: Rule,
+    /// Name or type of the commands whose requests are checked. All requests, including
+    /// the ones made while the extension was loading, if unset.
+    during: Option<String>,
+}
+
+impl HostCallAssertion {
+    pub(crate) fn parse(value: Value) -> Result<Self> {
+        let raw: RawAssertion = serde_json::from_value(value.clone()).map_err(|_| {
+            format!(
+                "Invalid host call assertion {}: expected a call, forbid or order rule",
+                value
+            )
+        })?;
+
+        Ok(match raw {
+            RawAssertion::Count(a) => {
+                let count = match a.count {
+                    Some(RawCount::Exact(count)) => Count {
+                        min: count,
+                        max: Some(count),
+                    },
+                    Some(RawCount::Range { min, max }) => Count {
+                        min: min.unwrap_or_default(),
+                        max,
+                    },
+                    None => Count { min: 1, max: None },
+                };
+                Self {
+                    rule: Rule::Count(CallPattern::new(a.call, a.matcher)?, count),
+                    during: a.during,
+                }
+            }
+            RawAssertion::Forbid(a) => Self {
+                rule: Rule::Forbid(CallPattern::new(a.forbid, a.matcher)?),
+                during: a.during,
+            },
+            RawAssertion::Order(a) => {
+                let patterns = a
+                    .order
+                    .into_iter()
+                    .map(|p| match p {
+                        RawPattern::Kind(kind) => CallPattern::new(kind, None),
+                        RawPattern::Call(call) => CallPattern::new(call.call, call.matcher),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Self {
+                    rule: Rule::Order(patterns),
+                    during: a.during,
+                }
+            }
+        })
+    }
+
+    fn scope(&self) -> String {
+        match &self.during {
+            Some(during) => format!(" during {}", during),
+            None => String::new(),
+        }
+    }
+
+    fn check(&self, log: &[(Option<&CommandReport>, &HostCall)]) -> Result<()> {
+        let calls: Vec<_> = log
+            .iter()
+            .filter(|(command, _)| {
+                self.during
+                    .as_ref()
+                    .is_none_or(|during| command.is_some_and(|c| is_command(c, during)))
+            })
+            .collect();
+
+        match &self.rule {
+            Rule::Count(pattern, count) => {
+                let called = calls
+                    .iter()
+                    .filter(|(_, call)| pattern.matches(call))
+                    .count();
+                if !count.contains(called) {
+                    return Err(format!(
+                        "Expected {} to be called {} times{}, it was called {} times",
+                        pattern,
+                        count,
+                        self.scope(),
+                        called
+                    )
+                    .into());
+                }
+            }
+            Rule::Forbid(pattern) => {
+                let places: Vec<_> = calls
+                    .iter()
+                    .filter(|(_, call)| pattern.matches(call))
+                    .map(|(command, _)| match command {
+                        Some(c) => format!("{} [{}] {}", c.phase.label(), c.index + 1, c.name),
+                        None => "startup".to_string(),
+                    })
+                    .collect();
+                if !places.is_empty() {
+                    return Err(format!(
+                        "{} must not be called{}, it was called during:\n  {}",
+                        pattern,
+                        self.scope(),
+                        places.join("\n  ")
+                    )
+                    .into());
+                }
+            }
+            Rule::Order(patterns) => {
+                let mut remaining = calls.iter();
+                for (i, pattern) in patterns.iter().enumerate() {
+                    if !remaining.any(|(_, call)| pattern.matches(call)) {
+                        let after = match i {
+                            0 => String::new(),
+                            _ => format!(" after {}", patterns[i - 1]),
+                        };
+                        let order = patterns
+                            .iter()
+                            .map(|p| p.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        return Err(format!(
+                            "Expected host calls in order {}{}, but {} was not called{}",
+                            order,
+                            self.scope(),
+                            pattern,
+                            after
+                        )
+                        .into());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for HostCallAssertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.rule {
+            Rule::Count(pattern, count) => write!(f, "call {} {} times", pattern, count)?,
+            Rule::Forbid(pattern) => write!(f, "forbid {}", pattern)?,
+            Rule::Order(patterns) => write!(
+                f,
+                "order {}",
+                patterns
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?,
+        }
+        f.write_str(&self.scope())
+    }
+}
+
+/// Whether `during` is the type of the command or its name, which is the start of its label.
+fn is_command(command: &CommandReport, during: &str) -> bool {
+    command.kind == during
+        || command
+            .name
+            .strip_prefix(during)
+            .is_some_and(|rest| rest.starts_with(" ("))
+}
+
+/// Checks every assertion against the host requests of a run, in the order they were made,
+/// and returns the assertions that were not checked, with the reason.
+///
+/// `complete` is false when commands were skipped or never sent. The requests of the run
+/// are then incomplete, so assertions over the whole run are not checked, and `during`
+/// assertions only against the commands they apply to that ran. `during` assertions on
+/// commands that ran in a parallel group are never checked, as the requests of a group
+/// cannot be told apart.
+pub(crate) fn check_host_calls(
+    assertions: &[HostCallAssertion],
+    startup_calls: &[HostCall],
+    commands: &[CommandReport],
+    complete: bool,
+) -> (Vec<String>, Result<()>) {
+    let log: Vec<_> = startup_calls
+        .iter()
+        .map(|call| (None, call))
+        .chain(
+            commands
+                .iter()
+                .flat_map(|c| c.host_calls.iter().map(move |call| (Some(c), call))),
+        )
+        .collect();
+
+    let mut checked = Vec::new();
+    let mut unchecked = Vec::new();
+    for assertion in assertions {
+        // Commands of the `during` scope that ran
+        let ran: Vec<_> = commands
+            .iter()
+            .filter(|c| {
+                c.status != CommandStatus::Skipped
+                    && assertion.during.as_ref().is_some_and(|d| is_command(c, d))
+            })
+            .collect();
+        let reason = if ran.iter().any(|c| c.parallel.is_some()) {
+            Some("its commands ran in a parallel group")
+        } else if complete || !ran.is_empty() {
+            None
+        } else {
+            Some("not every command ran")
+        };
+        match reason {
+            Some(reason) => unchecked.push(format!("{} ({})", assertion, reason)),
+            None => checked.push(assertion),
+        }
+    }
+
+    let errors: Vec<_> = checked
+        .into_iter()
+        .filter_map(|a| a.check(&log).err())
+        .map(|e| e.to_string())
+        .collect();
+
+    if errors.is_empty() {
+        (unchecked, Ok(()))
+    } else {
+        (
+            unchecked,
+            Err(MoosyncError::String(format!(
+                "Host call assertions failed:\n{}",
+                errors.join("\n")
+            ))),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{AnsweredBy, Phase};
+
+    fn call(kind: &str, data: Value) -> HostCall {
+        HostCall {
+            kind: kind.to_string(),
+            data,
+            delay_ms: 0.0,
+            duration_ms: 0.0,
+            error: None,
+            answered_by: AnsweredBy::Default,
+        }
+    }
+
+    fn command(index: usize, kind: &str, host_calls: Vec<HostCall>) -> CommandReport {
+        CommandReport {
+            phase: Phase::Test,
+            index,
+            name: format!("{} (ExtensionExtraEvent)", kind),
+            kind: kind.to_string(),
+            status: CommandStatus::Passed,
+            response: None,
+            error: None,
+            duration_ms: 0.0,
+            memory: None,
+            fuel: None,
+            parallel: None,
+            host_calls,
+        }
+    }
+
+    fn assertion(value: Value) -> HostCallAssertion {
+        HostCallAssertion::parse(value).unwrap()
+    }
+
+    fn commands() -> Vec<CommandReport> {
+        vec![
+            command(
+                0,
+                "requestedSearchResult",
+                vec![call("getSecure", json!({ "key": "session" }))],
+            ),
+            command(
+                1,
+                "songChanged",
+                vec![
+                    call("getPreference", json!({ "key": "scrobble" })),
+                    call("setSecure", json!({ "key": "session" })),
+                ],
+            ),
+        ]
+    }
+
+    fn check(
+        assertions: Vec<Value>,
+        commands: &[CommandReport],
+        complete: bool,
+    ) -> (Vec<String>, bool) {
+        let assertions: Vec<_> = assertions.into_iter().map(assertion).collect();
+        let (unchecked, result) = check_host_calls(&assertions, &[], commands, complete);
+        (unchecked, result.is_ok())
+    }
+
+    #[test]
+    fn counts_and_forbidden_calls_respect_during() {
+        let commands = commands();
+        assert!(
+            check(
+                vec![json!({ "call": "getSecure", "count": 1 })],
+                &commands,
+                true
+            )
+            .1
+        );
+        assert!(
+            !check(
+                vec![json!({ "call": "getSecure", "count": { "min": 2 } })],
+                &commands,
+                true
+            )
+            .1
+        );
+        assert!(
+            check(
+                vec![json!({ "forbid": "setSecure", "during": "requestedSearchResult" })],
+                &commands,
+                true
+            )
+            .1
+        );
+        assert!(
+            !check(
+                vec![json!({ "forbid": "setSecure", "during": "songChanged" })],
+                &commands,
+                true
+            )
+            .1
+        );
+        assert!(
+            !check(
+                vec![json!({ "forbid": "getSecure", "match": { "subset": { "key": "session" } } })],
+                &commands,
+                true
+            )
+            .1
+        );
+    }
+
+    #[test]
+    fn order_allows_calls_in_between() {
+        let commands = commands();
+        assert!(
+            check(
+                vec![json!({ "order": ["getSecure", "setSecure"] })],
+                &commands,
+                true
+            )
+            .1
+        );
+        assert!(
+            !check(
+                vec![json!({ "order": ["setSecure", "getSecure"] })],
+                &commands,
+                true
+            )
+            .1
+        );
+    }
+
+    #[test]
+    fn incomplete_runs_only_check_during_assertions_of_commands_that_ran() {
+        let mut commands = commands();
+        commands[1].status = CommandStatus::Skipped;
+        let (unchecked, ok) = check(
+            vec![
+                json!({ "forbid": "setSecure" }),
+                json!({ "call": "getSecure", "during": "requestedSearchResult" }),
+                json!({ "call": "setSecure", "during": "songChanged" }),
+            ],
+            &commands,
+            false,
+        );
+        assert!(ok);
+        assert_eq!(
+            unchecked,
+            vec![
+                "forbid setSecure (not every command ran)",
+                "call setSecure at least 1 times during songChanged (not every command ran)"
+            ]
+        );
+    }
+
+    #[test]
+    fn during_assertions_on_parallel_groups_are_not_checked() {
+        let mut commands = commands();
+        commands[0].parallel = Some(0);
+        commands[1].parallel = Some(0);
+        let (unchecked, ok) = check(
+            vec![
+                json!({ "forbid": "setSecure", "during": "songChanged" }),
+                json!({ "call": "setSecure" }),
+            ],
+            &commands,
+            true,
+        );
+        assert!(ok);
+        assert_eq!(
+            unchecked,
+            vec!["forbid setSecure during songChanged (its commands ran in a parallel group)"]
+        );
+    }
+
+    #[test]
+    fn unknown_rules_are_rejected() {
+        assert!(HostCallAssertion::parse(json!({ "allow": "getSecure" })).is_err());
+        assert!(HostCallAssertion::parse(json!({ "call": "getSecure", "times": 1 })).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use colored::*;
+use serde::{Deserialize, Serialize};
+use types::errors::Result;
+
+use crate::{command_type, filter::Selection, replay::Replay, wasm::Instrumentation};
+
+/// Options of `moodriver bench`.
+pub(crate) struct BenchOptions {
+    pub(crate) manifest_path: PathBuf,
+    pub(crate) trace: PathBuf,
+    pub(crate) iterations: usize,
+    /// Runs of every command before measuring, which are not counted
+    pub(crate) warmup: usize,
+    pub(crate) selection: Selection,
+    pub(crate) verbose: u8,
+    /// File the results are written to as JSON
+    pub(crate) json: Option<PathBuf>,
+    /// Results of an earlier run to compare with
+    pub(crate) baseline: Option<PathBuf>,
+    /// Largest allowed increase of a median latency over the baseline, in percent
+    pub(crate) threshold: f64,
+}
+
+/// Latency of a command type over every measured run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CommandStats {
+    pub(crate) kind: String,
+    pub(crate) samples: usize,
+    pub(crate) min_ms: f64,
+    pub(crate) median_ms: f64,
+    pub(crate) p95_ms: f64,
+    pub(crate) p99_ms: f64,
+    /// Commands handled per second, back to back
+    pub(crate) throughput: f64,
+}
+
+impl CommandStats {
+    fn new(kind: String, mut durations: Vec<f64>) -> Self {
+        durations.sort_by(f64::total_cmp);
+        let percentile = |p: f64| {
+            let rank = (p / 100.0 * durations.len() as f64).ceil() as usize;
+            durations[rank.clamp(1, durations.len()) - 1]
+        };
+        let total: f64 = durations.iter().sum();
+
+        Self {
+            samples: durations.len(),
+            min_ms: durations[0],
+            median_ms: percentile(50.0),
+            p95_ms: percentile(95.0),
+            p99_ms: percentile(99.0),
+            throughput: if total > 0.0 {
+                durations.len() as f64 / total * 1000.0
+            } else {
+                0.0
+            },
+            kind,
+        }
+    }
+}
+
+/// Results of `moodriver bench`, as written by `--json` and read by `--baseline`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BenchReport {
+    pub(crate) trace: String,
+    pub(crate) iterations: usize,
+    pub(crate) commands: Vec<CommandStats>,
+}
+
+/// Sends the selected commands of the first case of a trace `iterations` times to a single
+/// loaded extension, after its setup commands and `warmup` unmeasured runs.
+pub(crate) async fn run_bench(opts: &BenchOptions) -> Result<()> {
+    let replay = Replay::load(
+        &opts.manifest_path,
+        &opts.trace,
+        &opts.selection,
+        opts.verbose,
+        Instrumentation::default(),
+    )
+    .await?;
+
+    println!(
+        "{} {} commands, {} warmup and {} measured runs",
+        "Benchmarking".blue(),
+        replay.commands.len(),
+        opts.warmup,
+        opts.iterations
+    );
+
+    let mut durations: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    for iteration in 0..opts.warmup + opts.iterations {
+        for command in &replay.commands {
+            let started = Instant::now();
+            replay
+                .send(command)
+                .await
+                .map_err(|e| format!("Run {}: {}", iteration + 1, e))?;
+            if iteration >= opts.warmup {
+                durations
+                    .entry(command_type(&command.command))
+                    .or_default()
+                    .push(started.elapsed().as_secs_f64() * 1000.0);
+            }
+        }
+        // Host requests are not reported while benchmarking
+        replay.take_calls();
+    }
+    replay.finish().await?;
+
+    let report = BenchReport {
+        trace: opts.trace.display().to_string(),
+        iterations: opts.iterations,
+        commands: durations
+            .into_iter()
+            .map(|(kind, durations)| CommandStats::new(kind, durations))
+            .collect(),
+    };
+    print_report(&report);
+
+    if let Some(path) = &opts.json {
+        fs::write(path, serde_json::to_string_pretty(&report)?)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+
+    match &opts.baseline {
+        Some(baseline) => compare(&report, &read_report(baseline)?, opts.threshold),
+        None => Ok(()),
+    }
+}
+
+fn print_report(report: &BenchReport) {
+    let width = report
+        .commands
+        .iter()
+        .map(|c| c.kind.len())
+        .max()
+        .unwrap_or_default()
+        .max("command".len());
+
+    println!(
+        "\n  {:width$} {:>7} {:>9} {:>9} {:>9} {:>9} {:>9}",
+        "command", "runs", "min", "median", "p95", "p99", "ops/s"
+    );
+    for stats in &report.commands {
+        println!(
+            "  {:width$} {:>7} {:>7.2}ms {:>7.2}ms {:>7.2}ms {:>7.2}ms {:>9.1}",
+            stats.kind.cyan(),
+            stats.samples,
+            stats.min_ms,
+            stats.median_ms,
+            stats.p95_ms,
+            stats.p99_ms,
+            stats.throughput
+        );
+    }
+}
+
+fn read_report(path: &Path) -> Result<BenchReport> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read baseline {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents)
+        .map_err(|e| format!("Invalid baseline {}: {}", path.display(), e).into())
+}
+
+/// Fails if the median latency of a command type grew by more than `threshold` percent.
+/// Command types missing from the baseline are not compared.
+fn compare(report: &BenchReport, baseline: &BenchReport, threshold: f64) -> Result<()> {
+    println!(
+        "\n{} (threshold {}%)",
+        "Compared to baseline".blue(),
+        threshold
+    );
+
+    let mut regressions = Vec::new();
+    for stats in &report.commands {
+        let Some(base) = baseline.commands.iter().find(|b| b.kind == stats.kind) else {
+            println!("  {:30} {}", stats.kind, "not in baseline".dimmed());
+            continue;
+        };
+
+        let change = if base.median_ms > 0.0 {
+            (stats.median_ms - base.median_ms) / base.median_ms * 100.0
+        } else {
+            0.0
+        };
+        let line = format!(
+            "  {:30} {:.2}ms -> {:.2}ms ({:+.1}%)",
+            stats.kind, base.median_ms, stats.median_ms, change
+        );
+        if change > threshold {
+            println!("{}", line.red());
+            regressions.push(format!(
+                "{} median latency regressed by {:.1}%",
+                stats.kind, change
+            ));
+        } else {
+            println!("{}", line.green());
+        }
+    }
+
+    if regressions.is_empty() {
+        Ok(())
+    } else {
+        Err(regressions.join("\n").into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(durations: &[f64]) -> CommandStats {
+        CommandStats::new("seeked".to_string(), durations.to_vec())
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let durations = (1..=100).rev().map(f64::from).collect::<Vec<_>>();
+        let hundred = stats(&durations);
+        assert_eq!(hundred.kind, "seeked");
+        assert_eq!(hundred.samples, 100);
+        assert_eq!(hundred.min_ms, 1.0);
+        assert_eq!(hundred.median_ms, 50.0);
+        assert_eq!(hundred.p95_ms, 95.0);
+        assert_eq!(hundred.p99_ms, 99.0);
+
+        let five = stats(&[4.0, 1.0, 3.0, 2.0, 5.0]);
+        assert_eq!(five.min_ms, 1.0);
+        assert_eq!(five.median_ms, 3.0);
+        assert_eq!(five.p95_ms, 5.0);
+        assert_eq!(five.p99_ms, 5.0);
+    }
+
+    #[test]
+    fn one_sample_is_every_percentile() {
+        let stats = stats(&[7.5]);
+        assert_eq!(stats.samples, 1);
+        for value in [stats.min_ms, stats.median_ms, stats.p95_ms, stats.p99_ms] {
+            assert_eq!(value, 7.5);
+        }
+    }
+
+    #[test]
+    fn throughput_is_commands_per_second() {
+        assert_eq!(stats(&[2.0, 3.0, 5.0]).throughput, 300.0);
+        assert_eq!(stats(&[0.0, 0.0]).throughput, 0.0);
+    }
+}
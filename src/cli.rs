@@ -0,0 +1,463 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use clap::{ArgAction, Parser, Subcommand};
+use colored::*;
+use types::errors::{MoosyncError, Result};
+
+use crate::{
+    ResponderRegistry, RunOptions,
+    bench::{BenchOptions, run_bench},
+    collect_trace_files,
+    coverage::{EventCoverage, WasmCoverage, provider_scopes},
+    filter::Selection,
+    fuzz::{FuzzOptions, run_fuzz},
+    host::Latency,
+    inputs::InteractiveInputs,
+    manifest::{extension_entry, validate_manifest},
+    repl, run_trace,
+    soak::{SoakOptions, run_soak},
+    tracing::{create_log_buffer, create_verbose_log, flush_logs},
+    watch::{WatchTarget, watch},
+};
+
+#[derive(Parser, Debug, Clone)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to the trace file
+    #[arg(short = 't', long = "trace", conflicts_with = "dir")]
+    trace: Option<PathBuf>,
+
+    /// Path to the trace directory
+    #[arg(short = 'd', long = "dir", conflicts_with = "trace")]
+    dir: Option<PathBuf>,
+
+    /// Path to the extension manifest
+    #[arg(required = true)]
+    manifest_path: Option<PathBuf>,
+
+    #[arg(short = 'v', long = "verbose", default_value = "0", action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Only run traces or commands whose name matches this glob
+    #[arg(long = "filter")]
+    filter: Option<String>,
+
+    /// Only run traces or commands with this tag
+    #[arg(long = "tag")]
+    tags: Vec<String>,
+
+    /// Skip traces or commands with this tag
+    #[arg(long = "exclude-tag")]
+    exclude_tags: Vec<String>,
+
+    /// Data for an interactive command, as key=value. Can be passed multiple times
+    #[arg(long = "input", value_name = "KEY=VALUE")]
+    inputs: Vec<String>,
+
+    /// File with data for interactive commands, keyed by input name
+    #[arg(long = "inputs-file")]
+    inputs_file: Option<PathBuf>,
+
+    /// Answer host requests with a responder registered by a custom binary, before the
+    /// requests of the trace
+    #[arg(long = "responder", value_name = "NAME")]
+    responder: Option<String>,
+
+    /// Delay every host request by MS milliseconds, or a random delay in MIN-MAX.
+    /// The `delayMs` of a request mock takes precedence
+    #[arg(long = "host-latency", value_name = "MS|MIN-MAX")]
+    host_latency: Option<String>,
+
+    /// Seed for random --host-latency delays
+    #[arg(
+        long = "host-latency-seed",
+        value_name = "SEED",
+        requires = "host_latency"
+    )]
+    host_latency_seed: Option<u64>,
+
+    /// Rerun traces when the extension, its manifest or the traces change
+    #[arg(short = 'w', long = "watch")]
+    watch: bool,
+
+    /// Run every trace and print which command types each of them sends
+    #[arg(long = "coverage", conflicts_with = "watch")]
+    coverage: bool,
+
+    /// Instrument the wasm module of the extension and write how often its code ran in
+    /// the traces to this file, in lcov format
+    #[arg(
+        long = "wasm-coverage",
+        value_name = "LCOV_FILE",
+        conflicts_with = "watch"
+    )]
+    wasm_coverage: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Load an extension and send it commands interactively
+    Repl {
+        /// Path to the extension manifest
+        manifest_path: PathBuf,
+
+        #[arg(short = 'v', long = "verbose", default_value = "0", action = ArgAction::Count)]
+        verbose: u8,
+    },
+    /// Measure how long the extension takes to handle the commands of a trace
+    Bench {
+        /// Path to the extension manifest
+        manifest_path: PathBuf,
+
+        /// Path to the trace file
+        #[arg(short = 't', long = "trace")]
+        trace: PathBuf,
+
+        /// Number of measured runs of every command
+        #[arg(short = 'n', long = "iterations", default_value = "100")]
+        iterations: usize,
+
+        /// Number of runs of every command before measuring
+        #[arg(long = "warmup", default_value = "5")]
+        warmup: usize,
+
+        /// Only benchmark commands whose name matches this glob
+        #[arg(long = "filter")]
+        filter: Option<String>,
+
+        /// Write the results to this file as JSON
+        #[arg(long = "json")]
+        json: Option<PathBuf>,
+
+        /// Results of an earlier run, written with --json, to compare with
+        #[arg(long = "baseline")]
+        baseline: Option<PathBuf>,
+
+        /// Largest allowed increase of a median latency over the baseline, in percent
+        #[arg(long = "threshold", default_value = "10", requires = "baseline")]
+        threshold: f64,
+
+        #[arg(short = 'v', long = "verbose", default_value = "0", action = ArgAction::Count)]
+        verbose: u8,
+    },
+    /// Replay the commands of a trace in a loop and look for steady memory growth
+    Soak {
+        /// Path to the extension manifest
+        manifest_path: PathBuf,
+
+        /// Path to the trace file
+        #[arg(short = 't', long = "trace")]
+        trace: PathBuf,
+
+        /// Number of iterations. Defaults to 1000 unless --duration is set
+        #[arg(short = 'n', long = "iterations")]
+        iterations: Option<usize>,
+
+        /// Stop after this many seconds
+        #[arg(long = "duration", value_name = "SECS")]
+        duration: Option<u64>,
+
+        /// Only replay commands whose name matches this glob
+        #[arg(long = "filter")]
+        filter: Option<String>,
+
+        /// Linear memory growth below this many megabytes is not reported as a leak
+        #[arg(long = "max-growth-mb", default_value = "1")]
+        max_growth_mb: f64,
+
+        #[arg(short = 'v', long = "verbose", default_value = "0", action = ArgAction::Count)]
+        verbose: u8,
+    },
+    /// Send random commands and host responses to the extension to find crashes
+    Fuzz {
+        /// Path to the extension manifest
+        manifest_path: PathBuf,
+
+        /// Number of commands to send
+        #[arg(short = 'n', long = "iterations", default_value = "500")]
+        iterations: usize,
+
+        /// Seed for generating commands, to reproduce an earlier run
+        #[arg(long = "seed")]
+        seed: Option<u64>,
+
+        /// Time a command may take before the extension is considered hung, in milliseconds
+        #[arg(long = "timeout-ms", default_value = "5000")]
+        timeout_ms: u64,
+
+        /// Directory reproducing traces are saved to
+        #[arg(long = "out", default_value = "fuzz")]
+        out: PathBuf,
+
+        /// Only send command types matching this glob
+        #[arg(long = "filter")]
+        filter: Option<String>,
+
+        #[arg(short = 'v', long = "verbose", default_value = "0", action = ArgAction::Count)]
+        verbose: u8,
+    },
+}
+
+/// Runs every case of a trace file, failing on the first case that does not pass.
+pub(crate) async fn run_trace_file(file: &Path, opts: &RunOptions) -> Result<()> {
+    for report in run_trace(file, opts).await? {
+        if let Some(error) = report.error {
+            return Err(format!("Test case {} failed:\n{}", report.name, error).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs every trace, even after a failure, and prints the command types they sent
+/// against the provider scopes of the extension.
+async fn run_coverage(traces: Vec<PathBuf>, opts: &RunOptions) -> Result<()> {
+    let mut coverage = EventCoverage::default();
+    let mut failures = Vec::new();
+    for trace in traces {
+        let reports = match run_trace(&trace, opts).await {
+            Ok(reports) => reports,
+            Err(e) => {
+                failures.push(format!("Trace {} failed:\n{}", trace.display(), e));
+                continue;
+            }
+        };
+        for report in &reports {
+            if let Some(error) = &report.error {
+                failures.push(format!("Test case {} failed:\n{}", report.name, error));
+            }
+        }
+        coverage.add(&trace, &reports);
+    }
+
+    let scopes = provider_scopes(opts).await?;
+    coverage.print(&scopes);
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures.join("\n\n").into())
+    }
+}
+
+async fn run_traces(args: &Cli, opts: &RunOptions) -> Result<()> {
+    if args.coverage {
+        let traces = match (&args.trace, &args.dir) {
+            (Some(trace), _) => vec![trace.clone()],
+            (None, Some(dir)) => collect_trace_files(dir),
+            (None, None) => unreachable!(),
+        };
+        run_coverage(traces, opts).await?;
+    } else if let Some(trace) = &args.trace {
+        run_trace_file(trace, opts).await?;
+    } else if let Some(dir) = &args.dir {
+        assert!(dir.exists(), "Traces directory {:?} does not exist", dir);
+
+        for trace in collect_trace_files(dir) {
+            run_trace_file(&trace, opts).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_cli(mut args: Cli, registry: &ResponderRegistry) -> Result<()> {
+    match &args.command {
+        Some(Command::Repl {
+            manifest_path,
+            verbose,
+        }) => return repl::run_repl(manifest_path, *verbose).await,
+        Some(Command::Bench {
+            manifest_path,
+            trace,
+            iterations,
+            warmup,
+            filter,
+            json,
+            baseline,
+            threshold,
+            verbose,
+        }) => {
+            validate_manifest(manifest_path)?;
+            return run_bench(&BenchOptions {
+                manifest_path: manifest_path.clone(),
+                trace: trace.clone(),
+                iterations: *iterations,
+                warmup: *warmup,
+                selection: Selection::new(filter.as_deref(), Vec::new(), Vec::new())?,
+                verbose: *verbose,
+                json: json.clone(),
+                baseline: baseline.clone(),
+                threshold: *threshold,
+            })
+            .await;
+        }
+        Some(Command::Soak {
+            manifest_path,
+            trace,
+            iterations,
+            duration,
+            filter,
+            max_growth_mb,
+            verbose,
+        }) => {
+            validate_manifest(manifest_path)?;
+            return run_soak(&SoakOptions {
+                manifest_path: manifest_path.clone(),
+                trace: trace.clone(),
+                iterations: *iterations,
+                duration: duration.map(Duration::from_secs),
+                selection: Selection::new(filter.as_deref(), Vec::new(), Vec::new())?,
+                verbose: *verbose,
+                max_growth_mb: *max_growth_mb,
+            })
+            .await;
+        }
+        Some(Command::Fuzz {
+            manifest_path,
+            iterations,
+            seed,
+            timeout_ms,
+            out,
+            filter,
+            verbose,
+        }) => {
+            validate_manifest(manifest_path)?;
+            return run_fuzz(&FuzzOptions {
+                manifest_path: manifest_path.clone(),
+                iterations: *iterations,
+                seed: *seed,
+                timeout: Duration::from_millis(*timeout_ms),
+                out: out.clone(),
+                filter: filter
+                    .as_deref()
+                    .map(glob::Pattern::new)
+                    .transpose()
+                    .map_err(|e| MoosyncError::String(format!("Invalid filter: {}", e)))?,
+                verbose: *verbose,
+            })
+            .await;
+        }
+        None => {}
+    }
+
+    println!(
+        "{}",
+        "=== Starting test CLI for WASM extensions ===\n".green()
+    );
+
+    if args.dir.is_none() {
+        args.dir = Some(PathBuf::from_str("./traces").unwrap())
+    }
+
+    let manifest_path = args
+        .manifest_path
+        .clone()
+        .ok_or_else(|| MoosyncError::String("Missing path to the extension manifest".into()))?;
+    validate_manifest(&manifest_path)?;
+
+    let opts = RunOptions {
+        manifest_path,
+        verbose: args.verbose,
+        selection: Selection::new(
+            args.filter.as_deref(),
+            args.tags.clone(),
+            args.exclude_tags.clone(),
+        )?,
+        inputs: InteractiveInputs::new(&args.inputs, args.inputs_file.as_deref())?,
+        mocks: Vec::new(),
+        responder: args
+            .responder
+            .as_deref()
+            .map(|name| registry.get(name))
+            .transpose()?,
+        host_latency: args
+            .host_latency
+            .as_deref()
+            .map(|latency| latency.parse::<Latency>())
+            .transpose()?
+            .map(|latency| latency.with_seed(args.host_latency_seed)),
+        print: true,
+        fail_fast: true,
+        wasm_coverage: args
+            .wasm_coverage
+            .as_ref()
+            .map(|_| Arc::new(Mutex::new(WasmCoverage::default()))),
+    };
+
+    if args.watch {
+        let target = match (&args.trace, &args.dir) {
+            (Some(trace), _) => WatchTarget::File(trace.clone()),
+            (None, Some(dir)) => WatchTarget::Dir(dir.clone()),
+            (None, None) => unreachable!(),
+        };
+        return watch(target, &opts).await;
+    }
+
+    let result = run_traces(&args, &opts).await;
+
+    // Written even if a trace failed, as the functions it called are still covered
+    if let (Some(path), Some(coverage)) = (&args.wasm_coverage, &opts.wasm_coverage) {
+        let coverage = coverage.lock().unwrap();
+        coverage.print();
+        coverage.write_lcov(path, &extension_entry(&opts.manifest_path)?)?;
+        println!("Wrote wasm coverage to {}", path.display());
+    }
+    result?;
+
+    println!(
+        "\n{}\n",
+        "=== All test commands completed successfully ===".green()
+    );
+
+    Ok(())
+}
+
+/// Entry point of the `moodriver` binary.
+pub async fn main() -> ExitCode {
+    main_with(ResponderRegistry::new()).await
+}
+
+/// Entry point for a custom binary whose host responders can be selected with `--responder`.
+pub async fn main_with(registry: ResponderRegistry) -> ExitCode {
+    let args = Cli::parse();
+
+    let verbose = match &args.command {
+        Some(Command::Repl { verbose, .. })
+        | Some(Command::Bench { verbose, .. })
+        | Some(Command::Soak { verbose, .. })
+        | Some(Command::Fuzz { verbose, .. }) => *verbose,
+        None => args.verbose,
+    };
+    if verbose > 0 {
+        create_verbose_log(verbose);
+    } else {
+        create_log_buffer();
+    }
+
+    if let Err(e) = run_cli(args.clone(), &registry).await {
+        println!("\n=== Extension output ===\n",);
+        flush_logs();
+        println!("\n=== End Extension output ===\n",);
+        println!("{}", e.to_string().red());
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,514 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use colored::*;
+use gimli::{EndianSlice, LittleEndian};
+use serde_json::{Map, Value, json};
+use types::errors::{MoosyncError, Result};
+
+use crate::{
+    CommandStatus, RunOptions, TraceReport, ValidCommand,
+    events::{EXTENSION_COMMANDS, EXTENSION_EXTRA_EVENTS, SCOPE_EVENTS},
+    host::Host,
+    mocks::MockSet,
+    reply_handler, start_extension,
+    wasm::{CodeMap, FunctionCode, Instrumentation},
+};
+
+/// Which command types every trace of a suite sent to the extension, for `--coverage`.
+#[derive(Default)]
+pub(crate) struct EventCoverage {
+    traces: Vec<PathBuf>,
+    /// Positions in `traces` of the traces that sent each command type
+    sent: BTreeMap<String, BTreeSet<usize>>,
+}
+
+impl EventCoverage {
+    /// Records the commands sent by every case of a trace. Skipped commands are ignored.
+    pub(crate) fn add(&mut self, trace: &Path, reports: &[TraceReport]) {
+        let index = self.traces.len();
+        self.traces.push(trace.to_path_buf());
+
+        for command in reports.iter().flat_map(|r| &r.commands) {
+            if command.status != CommandStatus::Skipped {
+                self.sent
+                    .entry(command.kind.clone())
+                    .or_default()
+                    .insert(index);
+            }
+        }
+    }
+
+    /// Prints a matrix of every known command type against the traces that sent it.
+    /// Types used by one of the extension's provider `scopes` are flagged when no trace
+    /// sends them.
+    pub(crate) fn print(&self, scopes: &[String]) {
+        let claimed = |kind: &str| {
+            scopes
+                .iter()
+                .filter(|scope| {
+                    SCOPE_EVENTS.iter().any(|(name, events)| {
+                        name.eq_ignore_ascii_case(scope) && events.contains(&kind)
+                    })
+                })
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        let kinds: Vec<&str> = EXTENSION_EXTRA_EVENTS
+            .iter()
+            .chain(EXTENSION_COMMANDS)
+            .copied()
+            .collect();
+        let width = kinds.iter().map(|k| k.len()).max().unwrap_or_default();
+
+        println!("\n{}", "=== Event coverage ===".cyan());
+        let header = (1..=self.traces.len())
+            .map(|i| format!("{:>3}", i))
+            .collect::<String>();
+        println!("  {:width$} {}", "", header);
+
+        let mut covered = 0;
+        for kind in &kinds {
+            let sent = self.sent.get(*kind);
+            let cells = (0..self.traces.len())
+                .map(|i| {
+                    if sent.is_some_and(|s| s.contains(&i)) {
+                        format!("{:>3}", "✓").green().to_string()
+                    } else {
+                        format!("{:>3}", "·").dimmed().to_string()
+                    }
+                })
+                .collect::<String>();
+
+            if sent.is_some() {
+                covered += 1;
+                println!("  {:width$} {}", kind, cells);
+                continue;
+            }
+
+            let scopes = claimed(kind);
+            if scopes.is_empty() {
+                println!("  {} {}", format!("{:width$}", kind).dimmed(), cells);
+            } else {
+                println!(
+                    "  {} {} {}",
+                    format!("{:width$}", kind).red(),
+                    cells,
+                    format!("(scope {} is not covered)", scopes.join(", ")).red()
+                );
+            }
+        }
+
+        println!("\n{}", "Traces:".cyan());
+        for (i, trace) in self.traces.iter().enumerate() {
+            println!("  {:>3} {}", i + 1, trace.display());
+        }
+
+        let unknown: Vec<_> = scopes
+            .iter()
+            .filter(|scope| {
+                !SCOPE_EVENTS
+                    .iter()
+                    .any(|(name, _)| name.eq_ignore_ascii_case(scope))
+            })
+            .cloned()
+            .collect();
+        if !unknown.is_empty() {
+            println!(
+                "\n{} {}",
+                "Scopes without known event types:".yellow(),
+                unknown.join(", ")
+            );
+        }
+
+        println!(
+            "\n{} of {} event types covered",
+            covered.to_string().green(),
+            kinds.len()
+        );
+    }
+}
+
+/// How often the code of the extension's wasm module ran in any test, for
+/// `--wasm-coverage`.
+#[derive(Default)]
+pub(crate) struct WasmCoverage {
+    code: CodeMap,
+    /// How often every segment of `code` ran
+    counts: BTreeMap<u32, u64>,
+}
+
+impl WasmCoverage {
+    /// Adds the counts of a run. Every run instruments the same module, so they all
+    /// have the same code.
+    pub(crate) fn add(&mut self, code: &CodeMap, counts: &BTreeMap<u32, u64>) {
+        if self.code.functions.is_empty() {
+            self.code = code.clone();
+        }
+        for (segment, count) in counts {
+            let total = self.counts.entry(*segment).or_default();
+            *total = total.saturating_add(*count);
+        }
+    }
+
+    fn calls(&self, function: &FunctionCode) -> u64 {
+        self.counts
+            .get(&function.entry)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn print(&self) {
+        let called = self
+            .code
+            .functions
+            .values()
+            .filter(|function| self.calls(function) > 0)
+            .count();
+        println!(
+            "\n{} {} of {} wasm functions called",
+            "=== Wasm coverage ===".cyan(),
+            called.to_string().green(),
+            self.code.functions.len()
+        );
+    }
+
+    /// Writes the coverage as an lcov tracefile for `wasm`, see `lcov`.
+    pub(crate) fn write_lcov(&self, path: &Path, wasm: &Path) -> Result<()> {
+        let module =
+            fs::read(wasm).map_err(|e| format!("Failed to read {}: {}", wasm.display(), e))?;
+        let lines = LineTable::parse(&module)?;
+        fs::write(path, self.lcov(wasm, lines.as_ref()))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e).into())
+    }
+
+    /// Formats the coverage as an lcov tracefile. Functions and lines are mapped to their
+    /// source through the line tables of the module. Functions without any are listed
+    /// under the module itself, at the line of their index, so a module built without
+    /// debug info only has coverage of its functions.
+    fn lcov(&self, wasm: &Path, lines: Option<&LineTable>) -> String {
+        #[derive(Default)]
+        struct Record {
+            /// Line, symbol and calls of every function
+            functions: Vec<(u64, String, u64)>,
+            /// Runs of every line, the most of the segments on it
+            lines: BTreeMap<u64, u64>,
+        }
+        let mut records = BTreeMap::<String, Record>::new();
+
+        // Names may repeat, e.g. across generic instances
+        let mut names = HashMap::<&str, usize>::new();
+        for function in self.code.functions.values() {
+            *names.entry(&function.name).or_default() += 1;
+        }
+        for (index, function) in &self.code.functions {
+            let symbol = if names[function.name.as_str()] > 1 {
+                format!("{}#{}", function.name, index)
+            } else {
+                function.name.clone()
+            };
+            let (file, line) = match lines.and_then(|lines| lines.at(function.start as u64)) {
+                Some((file, line)) => (file.to_string(), line),
+                None => (wasm.display().to_string(), *index as u64),
+            };
+            records
+                .entry(file)
+                .or_default()
+                .functions
+                .push((line, symbol, self.calls(function)));
+        }
+
+        if let Some(lines) = lines {
+            for (index, segment) in self.code.segments.iter().enumerate() {
+                let count = self
+                    .counts
+                    .get(&(index as u32))
+                    .copied()
+                    .unwrap_or_default();
+                let code = segment.code.start as u64..segment.code.end as u64;
+                for (file, line) in lines.within(code) {
+                    let runs = records
+                        .entry(file.to_string())
+                        .or_default()
+                        .lines
+                        .entry(line)
+                        .or_default();
+                    *runs = (*runs).max(count);
+                }
+            }
+        }
+
+        let mut lcov = String::new();
+        for (file, record) in records {
+            lcov.push_str(&format!("TN:\nSF:{}\n", file));
+            for (line, symbol, _) in &record.functions {
+                lcov.push_str(&format!("FN:{},{}\n", line, symbol));
+            }
+            for (_, symbol, calls) in &record.functions {
+                lcov.push_str(&format!("FNDA:{},{}\n", calls, symbol));
+            }
+            lcov.push_str(&format!(
+                "FNF:{}\nFNH:{}\n",
+                record.functions.len(),
+                record.functions.iter().filter(|f| f.2 > 0).count()
+            ));
+            for (line, runs) in &record.lines {
+                lcov.push_str(&format!("DA:{},{}\n", line, runs));
+            }
+            if !record.lines.is_empty() {
+                lcov.push_str(&format!(
+                    "LF:{}\nLH:{}\n",
+                    record.lines.len(),
+                    record.lines.values().filter(|runs| **runs > 0).count()
+                ));
+            }
+            lcov.push_str("end_of_record\n");
+        }
+        lcov
+    }
+}
+
+/// Source lines of the code of a wasm module, from the DWARF line tables in its custom
+/// sections. Addresses are offsets in the code section, like in `CodeMap`.
+struct LineTable {
+    files: Vec<String>,
+    /// By address, the file and line of the code from there on, or `None` past the end
+    /// of a sequence
+    rows: Vec<(u64, Option<(usize, u64)>)>,
+}
+
+fn dwarf_error(e: impl std::fmt::Display) -> MoosyncError {
+    format!("Failed to read the line tables of the wasm module: {}", e).into()
+}
+
+impl LineTable {
+    /// Reads the line tables of a module, if it has any.
+    fn parse(module: &[u8]) -> Result<Option<Self>> {
+        let mut sections = HashMap::new();
+        for payload in wasmparser::Parser::new(0).parse_all(module) {
+            let wasmparser::Payload::CustomSection(section) = payload.map_err(dwarf_error)? else {
+                continue;
+            };
+            if section.name().starts_with(".debug_") {
+                sections.insert(section.name(), section.data());
+            }
+        }
+        if !sections.contains_key(".debug_line") {
+            return Ok(None);
+        }
+        let dwarf = gimli::Dwarf::load(|id| {
+            let data = sections.get(id.name()).copied().unwrap_or_default();
+            Ok::<_, gimli::Error>(EndianSlice::new(data, LittleEndian))
+        })
+        .map_err(dwarf_error)?;
+
+        let mut table = LineTable {
+            files: Vec::new(),
+            rows: Vec::new(),
+        };
+        let mut files = HashMap::new();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next().map_err(dwarf_error)? {
+            let unit = dwarf.unit(header).map_err(dwarf_error)?;
+            let Some(program) = unit.line_program.clone() else {
+                continue;
+            };
+            // Files are numbered per unit
+            let mut paths = HashMap::new();
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row().map_err(dwarf_error)? {
+                if row.end_sequence() {
+                    table.rows.push((row.address(), None));
+                    continue;
+                }
+                let (Some(entry), Some(line)) = (row.file(header), row.line()) else {
+                    continue;
+                };
+                let file = match paths.get(&row.file_index()) {
+                    Some(file) => *file,
+                    None => {
+                        let mut path = PathBuf::new();
+                        if let Some(dir) = &unit.comp_dir {
+                            path.push(dir.to_string_lossy().as_ref());
+                        }
+                        if let (true, Some(dir)) =
+                            (entry.directory_index() != 0, entry.directory(header))
+                        {
+                            let dir = dwarf.attr_string(&unit, dir).map_err(dwarf_error)?;
+                            path.push(dir.to_string_lossy().as_ref());
+                        }
+                        let name = dwarf
+                            .attr_string(&unit, entry.path_name())
+                            .map_err(dwarf_error)?;
+                        path.push(name.to_string_lossy().as_ref());
+
+                        let path = path.display().to_string();
+                        let file = *files.entry(path.clone()).or_insert_with(|| {
+                            table.files.push(path);
+                            table.files.len() - 1
+                        });
+                        paths.insert(row.file_index(), file);
+                        file
+                    }
+                };
+                table.rows.push((row.address(), Some((file, line.get()))));
+            }
+        }
+        table.rows.sort_by_key(|(address, _)| *address);
+        Ok(Some(table))
+    }
+
+    /// File and line of the code at an address.
+    fn at(&self, address: u64) -> Option<(&str, u64)> {
+        let row = self.rows.partition_point(|(start, _)| *start <= address);
+        let (_, location) = self.rows[..row].last()?;
+        location.map(|(file, line)| (self.files[file].as_str(), line))
+    }
+
+    /// Files and lines of the code in a range of addresses.
+    fn within(&self, code: Range<u64>) -> impl Iterator<Item = (&str, u64)> {
+        let first = self.rows.partition_point(|(start, _)| *start <= code.start);
+        let last = self.rows.partition_point(|(start, _)| *start < code.end);
+        self.at(code.start).into_iter().chain(
+            self.rows[first..last.max(first)]
+                .iter()
+                .filter_map(|(_, location)| *location)
+                .map(|(file, line)| (self.files[file].as_str(), line)),
+        )
+    }
+}
+
+/// Loads the extension on its own and asks for its provider scopes.
+pub(crate) async fn provider_scopes(opts: &RunOptions) -> Result<Vec<String>> {
+    let mut mocks = MockSet::new(&Map::new(), Path::new("."))?;
+    for mock in &opts.mocks {
+        mocks.push(mock.clone())?;
+    }
+    let host = Arc::new(Host::new(mocks, opts.responder.clone(), None, false));
+    let extension = start_extension(
+        &opts.manifest_path,
+        reply_handler(host),
+        opts.verbose,
+        false,
+        Instrumentation::default(),
+    )
+    .await?;
+
+    let command: ValidCommand = serde_json::from_value(json!({
+        "type": "getProviderScopes",
+        "data": { "packageName": extension.package_name },
+    }))?;
+    let scopes = extension.send(command).await?;
+
+    match scopes {
+        Value::Array(scopes) => Ok(scopes
+            .into_iter()
+            .map(|scope| match scope {
+                Value::String(scope) => scope,
+                other => other.to_string(),
+            })
+            .collect()),
+        Value::Null => Ok(Vec::new()),
+        other => Err(format!("Unexpected getProviderScopes response: {}", other).into()),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::wasm::{Segment, instrument};
+
+    use super::*;
+
+    const EXTENSION: &[u8] = include_bytes!("../tests/fixtures/extension/extension.wasm");
+
+    fn record<'a>(lcov: &'a str, file: &str) -> Vec<&'a str> {
+        lcov.split("end_of_record\n")
+            .find(|record| record.contains(&format!("SF:{}\n", file)))
+            .unwrap()
+            .lines()
+            .collect()
+    }
+
+    #[test]
+    fn maps_code_to_source_lines() {
+        let instrumented = instrument(
+            EXTENSION,
+            1,
+            Instrumentation {
+                coverage: true,
+                meter: None,
+                calls: false,
+            },
+        )
+        .unwrap();
+        let greet = instrumented
+            .code
+            .functions
+            .values()
+            .find(|function| function.name == "greet")
+            .unwrap();
+        let mut coverage = WasmCoverage::default();
+        // Two calls, which only ran the start of the function
+        coverage.add(&instrumented.code, &BTreeMap::from([(greet.entry, 1)]));
+        coverage.add(&instrumented.code, &BTreeMap::from([(greet.entry, 1)]));
+
+        let lines = LineTable::parse(EXTENSION).unwrap();
+        let lcov = coverage.lcov(Path::new("extension.wasm"), lines.as_ref());
+        let record = record(&lcov, "src/lib.rs");
+        for line in ["FN:3,greet", "FNDA:2,greet", "FNH:1", "DA:3,2", "DA:6,0"] {
+            assert!(record.contains(&line), "{} not in {:?}", line, record);
+        }
+    }
+
+    #[test]
+    fn lists_functions_by_index_without_line_tables() {
+        let function = |name: &str, entry| FunctionCode {
+            name: name.to_string(),
+            start: entry * 10,
+            entry,
+        };
+        let code = CodeMap {
+            functions: BTreeMap::from([
+                (2, function("main", 0)),
+                (3, function("helper", 1)),
+                (4, function("helper", 2)),
+            ]),
+            segments: (0..3)
+                .map(|segment| Segment {
+                    function: segment + 2,
+                    code: segment * 10..segment * 10 + 5,
+                })
+                .collect(),
+        };
+        let mut coverage = WasmCoverage::default();
+        coverage.add(&code, &BTreeMap::from([(0, 5), (2, u32::MAX as u64)]));
+        coverage.add(&code, &BTreeMap::from([(2, 1)]));
+
+        assert!(
+            LineTable::parse(&wasm_encoder::Module::new().finish())
+                .unwrap()
+                .is_none()
+        );
+        let lcov = coverage.lcov(Path::new("extension.wasm"), None);
+        assert_eq!(
+            record(&lcov, "extension.wasm"),
+            [
+                "TN:",
+                "SF:extension.wasm",
+                "FN:2,main",
+                "FN:3,helper#3",
+                "FN:4,helper#4",
+                "FNDA:5,main",
+                "FNDA:0,helper#3",
+                "FNDA:4294967296,helper#4",
+                "FNF:3",
+                "FNH:2",
+            ]
+        );
+    }
+}
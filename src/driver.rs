@@ -0,0 +1,183 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde_json::Value;
+use types::errors::Result;
+
+use crate::{
+    HostResponder, RunOptions, TraceReport, collect_trace_files, filter::Selection, host::Latency,
+    inputs::InteractiveInputs, manifest::validate_manifest, run_trace,
+};
+
+/// Runs traces against an extension from Rust code, e.g. an integration test.
+///
+/// ```no_run
+/// # async fn example() {
+/// let reports = moodriver::Driver::new("extension/manifest.json")
+///     .mock(serde_json::json!({ "type": "getVolume", "data": 50 }))
+///     .run_trace("traces/search.json")
+///     .await
+///     .unwrap();
+/// assert!(reports.iter().all(|r| r.passed));
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Driver {
+    manifest_path: PathBuf,
+    mocks: Vec<Value>,
+    responder: Option<Arc<dyn HostResponder>>,
+    inputs: Vec<String>,
+    filter: Option<String>,
+    tags: Vec<String>,
+    exclude_tags: Vec<String>,
+    host_latency: Option<String>,
+    host_latency_seed: Option<u64>,
+    verbose: u8,
+    print: bool,
+}
+
+impl Driver {
+    pub fn new(manifest_path: impl Into<PathBuf>) -> Self {
+        Self {
+            manifest_path: manifest_path.into(),
+            mocks: Vec::new(),
+            responder: None,
+            inputs: Vec::new(),
+            filter: None,
+            tags: Vec::new(),
+            exclude_tags: Vec::new(),
+            host_latency: None,
+            host_latency_seed: None,
+            verbose: 0,
+            print: false,
+        }
+    }
+
+    /// Adds a request mock, in the same format as the `requests` of a trace.
+    /// Mocks defined by the trace itself take precedence.
+    pub fn mock(mut self, mock: Value) -> Self {
+        self.mocks.push(mock);
+        self
+    }
+
+    /// Answers host requests in Rust. Requests the responder does not answer fall back to
+    /// the mocks of the trace and the ones added with [`Driver::mock`].
+    pub fn responder(mut self, responder: impl HostResponder + 'static) -> Self {
+        self.responder = Some(Arc::new(responder));
+        self
+    }
+
+    /// Provides the data for an interactive command, like `--input key=value`.
+    pub fn input(mut self, key: &str, value: &str) -> Self {
+        self.inputs.push(format!("{}={}", key, value));
+        self
+    }
+
+    /// Only runs traces or commands whose name matches this glob, like `--filter`.
+    pub fn filter(mut self, filter: &str) -> Self {
+        self.filter = Some(filter.to_string());
+        self
+    }
+
+    /// Only runs traces or commands with this tag, like `--tag`.
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    /// Skips traces or commands with this tag, like `--exclude-tag`.
+    pub fn exclude_tag(mut self, tag: &str) -> Self {
+        self.exclude_tags.push(tag.to_string());
+        self
+    }
+
+    /// Delays every host request, like `--host-latency`. Either `MS` or `MIN-MAX`.
+    pub fn host_latency(mut self, latency: &str) -> Self {
+        self.host_latency = Some(latency.to_string());
+        self
+    }
+
+    /// Seed for random host latencies, like `--host-latency-seed`.
+    pub fn host_latency_seed(mut self, seed: u64) -> Self {
+        self.host_latency_seed = Some(seed);
+        self
+    }
+
+    /// Prints progress to stdout like the CLI does, in addition to returning reports.
+    pub fn print(mut self, print: bool) -> Self {
+        self.print = print;
+        self
+    }
+
+    pub fn verbose(mut self, verbose: u8) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    fn options(&self) -> Result<RunOptions> {
+        validate_manifest(&self.manifest_path)?;
+
+        Ok(RunOptions {
+            manifest_path: self.manifest_path.clone(),
+            verbose: self.verbose,
+            selection: Selection::new(
+                self.filter.as_deref(),
+                self.tags.clone(),
+                self.exclude_tags.clone(),
+            )?,
+            inputs: InteractiveInputs::new(&self.inputs, None)?,
+            mocks: self.mocks.clone(),
+            responder: self.responder.clone(),
+            host_latency: self
+                .host_latency
+                .as_deref()
+                .map(|latency| latency.parse::<Latency>())
+                .transpose()?
+                .map(|latency| latency.with_seed(self.host_latency_seed)),
+            print: self.print,
+            fail_fast: false,
+            wasm_coverage: None,
+        })
+    }
+
+    /// Runs a trace file and returns one report per case.
+    pub async fn run_trace(&self, path: impl AsRef<Path>) -> Result<Vec<TraceReport>> {
+        run_trace(path.as_ref(), &self.options()?).await
+    }
+
+    /// Runs every trace file in a directory, skipping `fixtures` directories.
+    pub async fn run_dir(&self, dir: impl AsRef<Path>) -> Result<Vec<TraceReport>> {
+        let opts = self.options()?;
+        let mut reports = Vec::new();
+        for trace in collect_trace_files(dir.as_ref()) {
+            reports.extend(run_trace(&trace, &opts).await?);
+        }
+        Ok(reports)
+    }
+}
+
+/// Used by the code generated by `trace_test!`.
+#[doc(hidden)]
+pub mod __private {
+    use super::Driver;
+
+    pub fn run_trace_test(manifest_path: &str, trace: &str) {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to create tokio runtime");
+
+        let reports = runtime
+            .block_on(Driver::new(manifest_path).run_trace(trace))
+            .unwrap_or_else(|e| panic!("Failed to run trace {}: {}", trace, e));
+
+        let failed: Vec<String> = reports
+            .iter()
+            .filter(|r| !r.passed)
+            .map(|r| r.to_string())
+            .collect();
+        assert!(failed.is_empty(), "{}", failed.join("\n"));
+    }
+}
@@ -0,0 +1,60 @@
+/// Every `ExtensionExtraEvent` type accepted in traces, as written in the `type` field.
+pub(crate) const EXTENSION_EXTRA_EVENTS: &[&str] = &[
+    "requestedPlaylists",
+    "requestedPlaylistSongs",
+    "oauthCallback",
+    "songQueueChanged",
+    "seeked",
+    "volumeChanged",
+    "playerStateChanged",
+    "songChanged",
+    "preferenceChanged",
+    "playbackDetailsRequested",
+    "customRequest",
+    "requestedSongFromURL",
+    "requestedPlaylistFromURL",
+    "requestedSearchResult",
+    "requestedRecommendations",
+    "requestedLyrics",
+    "requestedArtistSongs",
+    "requestedAlbumSongs",
+    "songAdded",
+    "songRemoved",
+    "playlistAdded",
+    "playlistRemoved",
+    "requestedSongFromId",
+    "getRemoteURL",
+    "scrobble",
+    "requestedSongContextMenu",
+    "requestedPlaylistContextMenu",
+    "contextMenuAction",
+];
+
+/// Every `ExtensionCommand` type accepted in traces, as written in the `type` field.
+pub(crate) const EXTENSION_COMMANDS: &[&str] =
+    &["getProviderScopes", "getAccounts", "performAccountLogin"];
+
+/// Types of the commands Moosync sends to extensions that return a provider scope from
+/// `getProviderScopes`.
+pub(crate) const SCOPE_EVENTS: &[(&str, &[&str])] = &[
+    ("search", &["requestedSearchResult"]),
+    ("playlists", &["requestedPlaylists"]),
+    ("playlistSongs", &["requestedPlaylistSongs"]),
+    ("artistSongs", &["requestedArtistSongs"]),
+    ("albumSongs", &["requestedAlbumSongs"]),
+    ("recommendations", &["requestedRecommendations"]),
+    ("scrobbles", &["scrobble"]),
+    ("playlistFromUrl", &["requestedPlaylistFromURL"]),
+    ("songFromUrl", &["requestedSongFromURL"]),
+    ("songFromId", &["requestedSongFromId"]),
+    ("lyrics", &["requestedLyrics"]),
+    (
+        "songContextMenu",
+        &["requestedSongContextMenu", "contextMenuAction"],
+    ),
+    (
+        "playlistContextMenu",
+        &["requestedPlaylistContextMenu", "contextMenuAction"],
+    ),
+    ("accounts", &["getAccounts", "performAccountLogin"]),
+];
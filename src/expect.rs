@@ -0,0 +1,168 @@
+use std::fmt;
+
+use regex::Regex;
+use serde::Deserialize;
+use types::errors::{MoosyncError, Result};
+
+use crate::{LIMIT_ERROR, TIMEOUT_ERROR, is_panic};
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawErrorMatcher {
+    Message(String),
+    Regex { regex: String },
+    Kind { kind: String },
+}
+
+/// The error a command is expected to fail with, from `expectedError`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(try_from = "RawErrorMatcher")]
+pub(crate) enum ErrorMatcher {
+    /// Matches errors whose message contains this text
+    Message(String),
+    Regex(Regex),
+    /// Matches errors by their `MoosyncError` variant, e.g. `String`, or `Panic`,
+    /// `Timeout` and `Limit` for failures no other matcher accepts
+    Kind(String),
+}
+
+impl TryFrom<RawErrorMatcher> for ErrorMatcher {
+    type Error = String;
+
+    fn try_from(raw: RawErrorMatcher) -> std::result::Result<Self, Self::Error> {
+        Ok(match raw {
+            RawErrorMatcher::Message(text) => ErrorMatcher::Message(text),
+            RawErrorMatcher::Regex { regex } => ErrorMatcher::Regex(
+                Regex::new(&regex)
+                    .map_err(|e| format!("Invalid expectedError regex {}: {}", regex, e))?,
+            ),
+            RawErrorMatcher::Kind { kind } => ErrorMatcher::Kind(kind),
+        })
+    }
+}
+
+impl fmt::Display for ErrorMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorMatcher::Message(text) => write!(f, "{:?}", text),
+            ErrorMatcher::Regex(regex) => write!(f, "/{}/", regex),
+            ErrorMatcher::Kind(kind) => write!(f, "kind {}", kind),
+        }
+    }
+}
+
+/// The variant name of an error, e.g. `String` for `MoosyncError::String(..)`.
+fn error_kind(error: &MoosyncError) -> String {
+    format!("{:?}", error)
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Failures that are never expected unless `kind` names them: the extension panicking, not
+/// responding in time, or going over a resource limit. Otherwise `""` would let a crashing
+/// extension pass.
+fn abnormal_kind(message: &str) -> Option<&'static str> {
+    if message.starts_with(LIMIT_ERROR) {
+        Some("Limit")
+    } else if is_panic(message) {
+        Some("Panic")
+    } else if message.starts_with(TIMEOUT_ERROR) {
+        Some("Timeout")
+    } else {
+        None
+    }
+}
+
+impl ErrorMatcher {
+    pub(crate) fn check(&self, error: &MoosyncError) -> Result<()> {
+        let message = error.to_string();
+        let abnormal = abnormal_kind(&message);
+        let matched = match self {
+            ErrorMatcher::Message(text) => abnormal.is_none() && message.contains(text.as_str()),
+            ErrorMatcher::Regex(regex) => abnormal.is_none() && regex.is_match(&message),
+            ErrorMatcher::Kind(kind) => match abnormal {
+                Some(abnormal) => abnormal == kind,
+                None => error_kind(error) == *kind,
+            },
+        };
+
+        if matched {
+            Ok(())
+        } else {
+            Err(format!("Expected an error matching {}, received: {}", self, message).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(value: serde_json::Value) -> ErrorMatcher {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn error(message: &str) -> MoosyncError {
+        MoosyncError::String(message.to_string())
+    }
+
+    #[test]
+    fn message_and_regex_match_ordinary_errors() {
+        let not_found = error("Song not found: 42");
+        assert!(matcher("not found".into()).check(&not_found).is_ok());
+        assert!(matcher("".into()).check(&not_found).is_ok());
+        assert!(matcher("timeout".into()).check(&not_found).is_err());
+        let regex = matcher(serde_json::json!({ "regex": "^Song not found: \\d+$" }));
+        assert!(regex.check(&not_found).is_ok());
+        assert!(regex.check(&error("Song not found: x")).is_err());
+    }
+
+    #[test]
+    fn kind_matches_the_error_variant() {
+        let kind = matcher(serde_json::json!({ "kind": "String" }));
+        assert!(kind.check(&error("anything")).is_ok());
+        assert!(
+            matcher(serde_json::json!({ "kind": "Io" }))
+                .check(&error("anything"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn abnormal_failures_only_match_their_kind() {
+        let timeout = error(&format!("{} 100ms", TIMEOUT_ERROR));
+        assert!(matcher("".into()).check(&timeout).is_err());
+        assert!(
+            matcher(serde_json::json!({ "regex": ".*" }))
+                .check(&timeout)
+                .is_err()
+        );
+        assert!(
+            matcher(serde_json::json!({ "kind": "String" }))
+                .check(&timeout)
+                .is_err()
+        );
+        assert!(
+            matcher(serde_json::json!({ "kind": "Timeout" }))
+                .check(&timeout)
+                .is_ok()
+        );
+
+        let limit = error(&format!("{} executed 10 instructions", LIMIT_ERROR));
+        assert!(matcher("instructions".into()).check(&limit).is_err());
+        assert!(
+            matcher(serde_json::json!({ "kind": "Limit" }))
+                .check(&limit)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn invalid_regexes_are_rejected_on_load() {
+        assert!(
+            serde_json::from_value::<ErrorMatcher>(serde_json::json!({ "regex": "(" })).is_err()
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use glob::Pattern;
+use types::errors::{MoosyncError, Result};
+
+use crate::{CommandWrapper, TestRun};
+
+/// Decides which traces and commands are executed, based on the `--filter`,
+/// `--tag` and `--exclude-tag` CLI options and the `skip` / `only` fields of commands.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Selection {
+    filter: Option<Pattern>,
+    tags: Vec<String>,
+    exclude_tags: Vec<String>,
+}
+
+impl Selection {
+    pub(crate) fn new(
+        filter: Option<&str>,
+        tags: Vec<String>,
+        exclude_tags: Vec<String>,
+    ) -> Result<Self> {
+        let filter = filter
+            .map(Pattern::new)
+            .transpose()
+            .map_err(|e| MoosyncError::String(format!("Invalid filter: {}", e)))?;
+
+        Ok(Self {
+            filter,
+            tags,
+            exclude_tags,
+        })
+    }
+
+    /// Returns, for every command of the run, whether it should be sent to the extension.
+    pub(crate) fn select(&self, run: &TestRun) -> Vec<bool> {
+        let commands = &run.test_case.commands;
+        let has_only = commands.iter().any(|c| c.only);
+
+        commands
+            .iter()
+            .map(|command| {
+                if command.skip || (has_only && !command.only) {
+                    return false;
+                }
+
+                let tags = || run.test_case.tags.iter().chain(command.tags.iter());
+                if tags().any(|t| self.exclude_tags.contains(t)) {
+                    return false;
+                }
+                if !self.tags.is_empty() && !tags().any(|t| self.tags.contains(t)) {
+                    return false;
+                }
+
+                match &self.filter {
+                    Some(filter) => {
+                        filter.matches(&run.name)
+                            || command.name.as_deref().is_some_and(|n| filter.matches(n))
+                    }
+                    None => true,
+                }
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn command_label(command: &CommandWrapper, desc: &str) -> String {
+    match &command.name {
+        Some(name) => format!("{} ({})", name, desc),
+        None => desc.to_string(),
+    }
+}
@@ -0,0 +1,75 @@
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+use types::errors::{MoosyncError, Result};
+
+pub(crate) use moodriver_common::FIXTURES_DIR;
+
+use crate::format::{TraceCodec, TraceFormat};
+
+/// Replaces every `{"$file": "path/to/file.json#/json/pointer"}` object inside `value`
+/// with the contents of the referenced file.
+///
+/// Paths are resolved relative to `base_dir`. Fixtures may reference other fixtures,
+/// which are resolved relative to the fixture that references them.
+pub(crate) fn resolve_file_refs(value: &mut Value, base_dir: &Path) -> Result<()> {
+    resolve(value, base_dir, &mut Vec::new())
+}
+
+fn resolve(value: &mut Value, base_dir: &Path, stack: &mut Vec<PathBuf>) -> Result<()> {
+    match value {
+        Value::Object(map) => {
+            if let Some(Value::String(reference)) = map.get("$file").filter(|_| map.len() == 1) {
+                *value = load_fixture(reference, base_dir, stack)?;
+                return Ok(());
+            }
+
+            for v in map.values_mut() {
+                resolve(v, base_dir, stack)?;
+            }
+        }
+        Value::Array(arr) => {
+            for v in arr.iter_mut() {
+                resolve(v, base_dir, stack)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn load_fixture(reference: &str, base_dir: &Path, stack: &mut Vec<PathBuf>) -> Result<Value> {
+    let (file, pointer) = match reference.split_once('#') {
+        Some((file, pointer)) => (file, Some(pointer)),
+        None => (reference, None),
+    };
+
+    let path = base_dir.join(file);
+    let format = TraceFormat::from_path(&path).ok_or_else(|| {
+        MoosyncError::String(format!("Unsupported fixture file extension: {:?}", path))
+    })?;
+
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| MoosyncError::String(format!("Failed to read fixture {:?}: {}", path, e)))?;
+    if stack.contains(&canonical) {
+        return Err(format!("Fixture {:?} references itself", path).into());
+    }
+
+    let mut fixture = format.parse(&path)?;
+
+    stack.push(canonical);
+    resolve(&mut fixture, path.parent().unwrap_or(base_dir), stack)?;
+    stack.pop();
+
+    match pointer {
+        Some(pointer) => fixture.pointer(pointer).cloned().ok_or_else(|| {
+            MoosyncError::String(format!(
+                "JSON pointer '{}' not found in fixture {:?}",
+                pointer, path
+            ))
+        }),
+        None => Ok(fixture),
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::Path,
+};
+
+use json_comments::StripComments;
+use serde_json::Value;
+use types::errors::{MoosyncError, Result};
+
+pub(crate) use moodriver_common::{TraceFormat, is_trace_file};
+
+/// Reading and writing traces, fixtures and inputs files in the format of their extension.
+pub(crate) trait TraceCodec {
+    fn parse(self, path: &Path) -> Result<Value>;
+
+    fn serialize(self, value: &Value) -> Result<String>;
+}
+
+impl TraceCodec for TraceFormat {
+    fn parse(self, path: &Path) -> Result<Value> {
+        let value = match self {
+            TraceFormat::Json => {
+                let file = File::open(path).map_err(|e| open_error(path, e))?;
+                serde_json::from_reader(StripComments::new(BufReader::new(file)))?
+            }
+            TraceFormat::Yaml => {
+                let file = File::open(path).map_err(|e| open_error(path, e))?;
+                serde_yaml::from_reader(StripComments::new(BufReader::new(file)))
+                    .map_err(|e| MoosyncError::String(e.to_string()))?
+            }
+            TraceFormat::Json5 => {
+                let contents = fs::read_to_string(path).map_err(|e| open_error(path, e))?;
+                json5::from_str(&contents).map_err(|e| MoosyncError::String(e.to_string()))?
+            }
+            TraceFormat::Toml => {
+                let contents = fs::read_to_string(path).map_err(|e| open_error(path, e))?;
+                toml::from_str(&contents).map_err(|e| MoosyncError::String(e.to_string()))?
+            }
+        };
+
+        Ok(value)
+    }
+
+    /// Writes a trace in this format. JSON5 traces are written as plain JSON.
+    fn serialize(self, value: &Value) -> Result<String> {
+        let contents = match self {
+            TraceFormat::Json | TraceFormat::Json5 => serde_json::to_string_pretty(value)?,
+            TraceFormat::Yaml => {
+                serde_yaml::to_string(value).map_err(|e| MoosyncError::String(e.to_string()))?
+            }
+            // TOML has no null, so traces with null data cannot be written as TOML
+            TraceFormat::Toml => toml::to_string(value)
+                .map_err(|e| MoosyncError::String(format!("Cannot write TOML: {}", e)))?,
+        };
+
+        Ok(contents)
+    }
+}
+
+fn open_error(path: &Path, e: std::io::Error) -> MoosyncError {
+    MoosyncError::String(format!("Failed to read {:?}: {}", path, e))
+}
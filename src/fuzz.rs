@@ -0,0 +1,573 @@
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use colored::*;
+use glob::Pattern;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde_json::{Map, Value, json};
+use types::errors::{MoosyncError, Result};
+
+use crate::{
+    Extension, MainCommandParsable, TIMEOUT_ERROR, ValidCommand,
+    events::{EXTENSION_COMMANDS, EXTENSION_EXTRA_EVENTS},
+    generate::Generator,
+    host::Host,
+    mocks::MockSet,
+    reply_handler, start_extension,
+    wasm::Instrumentation,
+};
+
+/// Attempts at reproducing a failure with a smaller trace before giving up.
+const MAX_SHRINK_ATTEMPTS: usize = 100;
+
+/// Arrays longer than this are only shrunk by halving them.
+const MAX_SHRUNK_ITEMS: usize = 8;
+
+/// Options of `moodriver fuzz`.
+pub(crate) struct FuzzOptions {
+    pub(crate) manifest_path: PathBuf,
+    pub(crate) iterations: usize,
+    pub(crate) seed: Option<u64>,
+    /// Time a command may take before the extension is considered hung
+    pub(crate) timeout: Duration,
+    /// Directory reproducing traces are saved to
+    pub(crate) out: PathBuf,
+    /// Only send command types matching this glob
+    pub(crate) filter: Option<Pattern>,
+    pub(crate) verbose: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum FailureKind {
+    Panic,
+    Hang,
+    Malformed,
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FailureKind::Panic => "panic",
+            FailureKind::Hang => "hang",
+            FailureKind::Malformed => "malformed response",
+        })
+    }
+}
+
+struct Failure {
+    kind: FailureKind,
+    message: String,
+}
+
+/// A single fuzzed command and the host responses it gets, which together make a trace.
+#[derive(Clone)]
+struct Case {
+    command: Value,
+    requests: Vec<Value>,
+}
+
+/// A loaded extension whose host responses are replaced for every case.
+struct Target {
+    extension: Extension,
+    host: Arc<Host>,
+}
+
+impl Target {
+    /// Loads the extension with every call of an export reporting how it ended, which
+    /// tells a wasm trap from an error the extension returned.
+    async fn load(opts: &FuzzOptions) -> Result<Self> {
+        let mocks = MockSet::new(&Map::new(), Path::new("."))?;
+        let host = Arc::new(Host::new(mocks, None, None, false));
+        let extension = start_extension(
+            &opts.manifest_path,
+            reply_handler(host.clone()),
+            opts.verbose,
+            false,
+            Instrumentation {
+                coverage: false,
+                meter: None,
+                calls: true,
+            },
+        )
+        .await?;
+        Ok(Self { extension, host })
+    }
+
+    /// Sends the command of `case` with its host responses, and returns how it failed:
+    /// with a call of the extension that trapped, or with an error although every call
+    /// succeeded, as the host could not read the response. Errors the extension returns
+    /// on purpose are not failures.
+    async fn run(&self, case: &Case, timeout: Duration) -> Result<Option<Failure>> {
+        let mut mocks = MockSet::new(&Map::new(), Path::new("."))?;
+        for request in &case.requests {
+            mocks.push(request.clone())?;
+        }
+        *self.host.mocks.write().unwrap() = mocks;
+        self.host.take_calls();
+        self.extension.take_calls();
+
+        let command: ValidCommand = serde_json::from_value(case.command.clone())?;
+        let sent = tokio::time::timeout(timeout, self.extension.send(command)).await;
+
+        let error = match sent {
+            Err(_) => {
+                return Ok(Some(Failure {
+                    kind: FailureKind::Hang,
+                    message: format!("{} {}ms", TIMEOUT_ERROR, timeout.as_millis()),
+                }));
+            }
+            Ok(Ok(_)) => return Ok(None),
+            Ok(Err(e)) => e.to_string(),
+        };
+
+        let calls = self.extension.take_calls().unwrap_or_default();
+        let kind = if calls.trapped > 0 {
+            FailureKind::Panic
+        } else if calls.succeeded > 0 && calls.failed == 0 {
+            FailureKind::Malformed
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Failure {
+            kind,
+            message: error,
+        }))
+    }
+}
+
+/// Runs a case again to tell whether it still fails, see `minimize`.
+trait Reproduce {
+    async fn reproduce(&mut self, case: &Case) -> Result<Option<FailureKind>>;
+}
+
+/// Reproduces cases on a loaded extension, which is loaded again after a case that
+/// panicked or hung, as the extension may be unusable after that.
+struct Reproducer<'a> {
+    target: &'a mut Target,
+    opts: &'a FuzzOptions,
+}
+
+impl Reproduce for Reproducer<'_> {
+    async fn reproduce(&mut self, case: &Case) -> Result<Option<FailureKind>> {
+        let kind = self
+            .target
+            .run(case, self.opts.timeout)
+            .await?
+            .map(|f| f.kind);
+        if matches!(kind, Some(FailureKind::Panic | FailureKind::Hang)) {
+            *self.target = Target::load(self.opts).await?;
+        }
+        Ok(kind)
+    }
+}
+
+/// Generates a random command of type `kind` and random responses to every host request.
+/// Returns `None` if the generated payload is not accepted by the command types.
+fn generate_case(generator: &mut Generator, kind: &str, package_name: &str) -> Option<Case> {
+    let pointer = if EXTENSION_COMMANDS.contains(&kind) {
+        format!("/$defs/ExtensionCommand/{}", kind)
+    } else {
+        format!("/$defs/ExtensionExtraEvent/{}", kind)
+    };
+    let mut data = generator.data(&pointer)?;
+    // Commands for another package would not reach the extension
+    if let Some(package) = data.get_mut("packageName") {
+        *package = Value::String(package_name.to_string());
+    }
+    let command = json!({ "type": kind, "data": data });
+    serde_json::from_value::<ValidCommand>(command.clone()).ok()?;
+
+    let mut requests = Vec::new();
+    for request in generator.request_kinds() {
+        if !generator.rng().random_bool(0.7) {
+            continue;
+        }
+        let Some(data) = generator.data(&format!("/$defs/MainCommandParsable/{}", request)) else {
+            continue;
+        };
+        let mut mock = json!({ "type": request, "data": data });
+        if serde_json::from_value::<MainCommandParsable>(mock.clone()).is_err() {
+            continue;
+        }
+        // Generated keys would never match the requested ones
+        if request == "getPreference" || request == "getSecure" {
+            mock["default"] = Value::Bool(true);
+        }
+        requests.push(mock);
+    }
+
+    Some(Case { command, requests })
+}
+
+/// Smaller variants of `value`, from the most to the least reduced.
+fn shrinks(value: &Value) -> Vec<Value> {
+    let mut out = Vec::new();
+    match value {
+        Value::Array(items) => {
+            if items.len() > 1 {
+                let half = items.len() / 2;
+                out.push(Value::Array(items[..half].to_vec()));
+                out.push(Value::Array(items[half..].to_vec()));
+            }
+            if items.len() <= MAX_SHRUNK_ITEMS {
+                for i in 0..items.len() {
+                    let mut items = items.clone();
+                    items.remove(i);
+                    out.push(Value::Array(items));
+                }
+                for (i, item) in items.iter().enumerate() {
+                    for shrunk in shrinks(item) {
+                        let mut items = items.clone();
+                        items[i] = shrunk;
+                        out.push(Value::Array(items));
+                    }
+                }
+            }
+        }
+        Value::Object(map) => {
+            for key in map.keys() {
+                let mut map = map.clone();
+                map.remove(key);
+                out.push(Value::Object(map));
+            }
+            for (key, item) in map {
+                for shrunk in shrinks(item) {
+                    let mut map = map.clone();
+                    map.insert(key.clone(), shrunk);
+                    out.push(Value::Object(map));
+                }
+            }
+        }
+        Value::String(s) if !s.is_empty() => {
+            out.push(Value::String(String::new()));
+            let half: String = s.chars().take(s.chars().count() / 2).collect();
+            if !half.is_empty() {
+                out.push(Value::String(half));
+            }
+        }
+        Value::Number(n) if n.as_f64() != Some(0.0) => out.push(Value::from(0)),
+        Value::Bool(true) => out.push(Value::Bool(false)),
+        _ => {}
+    }
+    out
+}
+
+/// Removes host responses and reduces the payload of `case` for as long as it still fails
+/// in the same way. Hangs are kept as they are, as every attempt would wait out the timeout
+/// and leave the hung extension running.
+async fn minimize(mut case: Case, kind: FailureKind, runner: &mut impl Reproduce) -> Result<Case> {
+    if kind == FailureKind::Hang {
+        return Ok(case);
+    }
+    let mut attempts = 0;
+
+    let mut i = case.requests.len();
+    while i > 0 && attempts < MAX_SHRINK_ATTEMPTS {
+        i -= 1;
+        let mut candidate = case.clone();
+        candidate.requests.remove(i);
+        attempts += 1;
+        if runner.reproduce(&candidate).await? == Some(kind) {
+            case = candidate;
+        }
+    }
+
+    'shrink: while attempts < MAX_SHRINK_ATTEMPTS {
+        for data in shrinks(&case.command["data"]) {
+            let mut candidate = case.clone();
+            candidate.command["data"] = data;
+            if serde_json::from_value::<ValidCommand>(candidate.command.clone()).is_err() {
+                continue;
+            }
+
+            attempts += 1;
+            if runner.reproduce(&candidate).await? == Some(kind) {
+                case = candidate;
+                continue 'shrink;
+            }
+            if attempts >= MAX_SHRINK_ATTEMPTS {
+                break;
+            }
+        }
+        break;
+    }
+
+    Ok(case)
+}
+
+fn save_case(case: &Case, kind: FailureKind, message: &str, opts: &FuzzOptions) -> Result<PathBuf> {
+    fs::create_dir_all(&opts.out)
+        .map_err(|e| format!("Failed to create {}: {}", opts.out.display(), e))?;
+
+    let command_type = case.command["type"].as_str().unwrap_or("command");
+    let slug = kind.to_string().replace(' ', "-");
+    let path = (1..)
+        .map(|n| {
+            opts.out
+                .join(format!("{}-{}-{}.json", command_type, slug, n))
+        })
+        .find(|path| !path.exists())
+        .unwrap();
+
+    let mut command = json!({
+        "name": message.lines().next().unwrap_or_default(),
+        "type": case.command["type"],
+        "data": case.command["data"],
+    });
+    // Only commands of a parallel group time out, the trace would hang otherwise
+    if kind == FailureKind::Hang {
+        command = json!({
+            "parallel": [command],
+            "timeoutMs": opts.timeout.as_millis() as u64,
+        });
+    }
+
+    let trace = json!({
+        "name": format!("fuzz: {} in {}", kind, command_type),
+        "tags": ["fuzz"],
+        "commands": [command],
+        "requests": case.requests,
+    });
+    fs::write(&path, serde_json::to_string_pretty(&trace)?)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Sends random commands with random host responses to the extension, and saves a
+/// minimized trace for every distinct panic, hang or malformed response.
+pub(crate) async fn run_fuzz(opts: &FuzzOptions) -> Result<()> {
+    let kinds: Vec<&str> = EXTENSION_EXTRA_EVENTS
+        .iter()
+        .chain(EXTENSION_COMMANDS)
+        .copied()
+        .filter(|kind| opts.filter.as_ref().is_none_or(|f| f.matches(kind)))
+        .collect();
+    if kinds.is_empty() {
+        return Err("No command types match the filter".into());
+    }
+
+    let seed = opts.seed.unwrap_or_else(|| rand::rng().random());
+    println!(
+        "{} {} command types for {} iterations with seed {}",
+        "Fuzzing".blue(),
+        kinds.len(),
+        opts.iterations,
+        seed
+    );
+    let mut generator = Generator::new(StdRng::seed_from_u64(seed));
+
+    let mut target = Target::load(opts).await?;
+    let mut seen = HashSet::new();
+    let mut saved = Vec::new();
+    let mut skipped = 0;
+
+    for iteration in 0..opts.iterations {
+        let kind = kinds[iteration % kinds.len()];
+        let Some(case) = generate_case(&mut generator, kind, &target.extension.package_name) else {
+            skipped += 1;
+            continue;
+        };
+
+        let Some(failure) = target.run(&case, opts.timeout).await? else {
+            continue;
+        };
+
+        println!(
+            "{} {} in {}: {}",
+            format!("Iteration {}:", iteration + 1).red(),
+            failure.kind,
+            kind,
+            failure.message.lines().next().unwrap_or_default()
+        );
+        // The extension may be unusable after a panic or hang
+        target = Target::load(opts).await?;
+
+        if !seen.insert((kind, failure.kind)) {
+            continue;
+        }
+        let mut reproducer = Reproducer {
+            target: &mut target,
+            opts,
+        };
+        let case = minimize(case, failure.kind, &mut reproducer).await?;
+        let path = save_case(&case, failure.kind, &failure.message, opts)?;
+        println!("  Saved reproducing trace to {}", path.display());
+        saved.push(path);
+    }
+
+    if skipped > 0 {
+        println!(
+            "{}",
+            format!(
+                "{} generated payloads were not valid commands and were skipped",
+                skipped
+            )
+            .dimmed()
+        );
+    }
+
+    if saved.is_empty() {
+        println!("{}", "No failures found".green());
+        Ok(())
+    } else {
+        Err(MoosyncError::String(format!(
+            "Found {} distinct failures, reproducing traces saved to {}. Rerun with --seed {} to reproduce the run",
+            saved.len(),
+            opts.out.display(),
+            seed
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails like `kind` whenever `fails` holds, and counts the attempts.
+    struct Fake<F> {
+        fails: F,
+        kind: FailureKind,
+        attempts: usize,
+    }
+
+    impl<F: Fn(&Case) -> bool> Reproduce for Fake<F> {
+        async fn reproduce(&mut self, case: &Case) -> Result<Option<FailureKind>> {
+            self.attempts += 1;
+            Ok((self.fails)(case).then_some(self.kind))
+        }
+    }
+
+    fn case(data: Value) -> Case {
+        Case {
+            command: json!({ "type": "requestedSearchResult", "data": data }),
+            requests: vec![
+                json!({ "type": "getVolume", "data": 50 }),
+                json!({ "type": "getTime", "data": 10 }),
+                json!({ "type": "getCurrentSong", "data": null }),
+            ],
+        }
+    }
+
+    #[test]
+    fn shrinks_scalars_towards_empty() {
+        assert_eq!(shrinks(&json!("abcd")), [json!(""), json!("ab")]);
+        assert_eq!(shrinks(&json!("a")), [json!("")]);
+        assert_eq!(shrinks(&json!(-3.5)), [json!(0)]);
+        assert_eq!(shrinks(&json!(true)), [json!(false)]);
+        for value in [json!(""), json!(0), json!(false), Value::Null] {
+            assert!(shrinks(&value).is_empty());
+        }
+    }
+
+    #[test]
+    fn shrinks_collections_by_parts() {
+        assert_eq!(
+            shrinks(&json!([1, "a"])),
+            [
+                json!([1]),
+                json!(["a"]),
+                json!(["a"]),
+                json!([1]),
+                json!([0, "a"]),
+                json!([1, ""]),
+            ]
+        );
+        assert_eq!(
+            shrinks(&json!({ "a": true, "b": 0 })),
+            [
+                json!({ "b": 0 }),
+                json!({ "a": true }),
+                json!({ "a": false, "b": 0 })
+            ]
+        );
+
+        // Long arrays are only halved
+        let len = MAX_SHRUNK_ITEMS + 1;
+        let halves = shrinks(&Value::Array(vec![json!(1); len]));
+        assert_eq!(halves.len(), 2);
+        assert_eq!(halves[0].as_array().unwrap().len(), len / 2);
+        assert_eq!(halves[1].as_array().unwrap().len(), len - len / 2);
+    }
+
+    #[test]
+    fn minimizes_while_the_failure_reproduces() {
+        let mut runner = Fake {
+            fails: |case: &Case| {
+                case.requests.iter().any(|r| r["type"] == "getVolume")
+                    && case.command["data"][0]
+                        .as_str()
+                        .is_some_and(|s| s.starts_with('x'))
+            },
+            kind: FailureKind::Panic,
+            attempts: 0,
+        };
+        let minimized = futures::executor::block_on(minimize(
+            case(json!(["xyz123"])),
+            FailureKind::Panic,
+            &mut runner,
+        ))
+        .unwrap();
+
+        assert_eq!(minimized.command["data"], json!(["x"]));
+        assert_eq!(
+            minimized.requests,
+            [json!({ "type": "getVolume", "data": 50 })]
+        );
+        assert!(runner.attempts <= MAX_SHRINK_ATTEMPTS);
+    }
+
+    #[test]
+    fn minimizing_needs_the_same_failure() {
+        let mut runner = Fake {
+            fails: |_: &Case| true,
+            kind: FailureKind::Malformed,
+            attempts: 0,
+        };
+        let minimized = futures::executor::block_on(minimize(
+            case(json!(["xyz"])),
+            FailureKind::Panic,
+            &mut runner,
+        ))
+        .unwrap();
+        assert_eq!(minimized.command["data"], json!(["xyz"]));
+        assert_eq!(minimized.requests.len(), 3);
+    }
+
+    #[test]
+    fn hangs_are_not_minimized() {
+        let mut runner = Fake {
+            fails: |_: &Case| true,
+            kind: FailureKind::Hang,
+            attempts: 0,
+        };
+        let minimized = futures::executor::block_on(minimize(
+            case(json!(["xyz"])),
+            FailureKind::Hang,
+            &mut runner,
+        ))
+        .unwrap();
+        assert_eq!(minimized.requests.len(), 3);
+        assert_eq!(runner.attempts, 0);
+    }
+
+    #[test]
+    fn stops_after_the_attempt_limit() {
+        let mut runner = Fake {
+            fails: |_: &Case| true,
+            kind: FailureKind::Panic,
+            attempts: 0,
+        };
+        let mut case = case(json!(["xyz"]));
+        case.requests = vec![json!({ "type": "getVolume", "data": 50 }); MAX_SHRINK_ATTEMPTS + 10];
+        let minimized =
+            futures::executor::block_on(minimize(case, FailureKind::Panic, &mut runner)).unwrap();
+        assert_eq!(runner.attempts, MAX_SHRINK_ATTEMPTS);
+        assert_eq!(minimized.requests.len(), 10);
+        assert_eq!(minimized.command["data"], json!(["xyz"]));
+    }
+}
@@ -0,0 +1,323 @@
+use rand::{Rng, rngs::StdRng};
+use serde_json::{Map, Number, Value};
+
+/// The schema of traces, which describes the payload of every command and host request.
+const SCHEMA: &str = include_str!("../schema.json");
+
+/// Nesting depth after which optional properties and array items are left out.
+const MAX_DEPTH: usize = 6;
+
+/// Length of the one huge array or string a payload may contain.
+const HUGE_LEN: usize = 500;
+
+/// Strings likely to trip up extensions: empty, whitespace, odd unicode, control
+/// characters, format specifiers and paths.
+const ODD_STRINGS: &[&str] = &[
+    "",
+    " ",
+    "\n\t",
+    "🎵🎶 ñ Ω≈ç√∫ 日本語",
+    "\u{202E}txet lanoitcerid-ib",
+    "e\u{301}\u{301}\u{301}",
+    "\u{0}",
+    "\u{FEFF}zero width\u{200B}",
+    "%s%n%x",
+    "../../etc/passwd",
+    "null",
+    "-1",
+    "https://",
+    "💀",
+];
+
+/// Generates random values matching the JSON schema of traces.
+pub(crate) struct Generator {
+    schema: Value,
+    rng: StdRng,
+    /// Whether the current payload already has its huge array or string
+    huge: bool,
+}
+
+impl Generator {
+    pub(crate) fn new(rng: StdRng) -> Self {
+        Self {
+            schema: serde_json::from_str(SCHEMA).expect("schema.json is valid JSON"),
+            rng,
+            huge: false,
+        }
+    }
+
+    pub(crate) fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
+    /// Types of the host requests that can be mocked.
+    pub(crate) fn request_kinds(&self) -> Vec<String> {
+        self.schema
+            .pointer("/$defs/MainCommandParsable")
+            .and_then(Value::as_object)
+            .map(|defs| defs.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// A random `data` for the definition at `pointer`, e.g. `/$defs/ExtensionCommand/getAccounts`.
+    pub(crate) fn data(&mut self, pointer: &str) -> Option<Value> {
+        let schema = self
+            .schema
+            .pointer(&format!("{}/properties/data", pointer))?
+            .clone();
+        self.huge = false;
+        Some(self.value(&schema, 0))
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        self.rng.random_bool(p)
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.rng.random_range(0..items.len())]
+    }
+
+    fn value(&mut self, schema: &Value, depth: usize) -> Value {
+        let schema = match schema {
+            Value::Bool(false) => return Value::Null,
+            Value::Object(schema) => schema,
+            _ => return self.any(),
+        };
+
+        if let Some(pointer) = schema.get("$ref").and_then(Value::as_str) {
+            let target = pointer
+                .strip_prefix('#')
+                .and_then(|p| self.schema.pointer(p))
+                .cloned()
+                .unwrap_or(Value::Bool(true));
+            return self.value(&target, depth);
+        }
+        if let Some(value) = schema.get("const") {
+            return value.clone();
+        }
+        if let Some(Value::Array(values)) = schema.get("enum") {
+            return self.pick(values).clone();
+        }
+        if let Some(Value::Array(options)) = schema.get("oneOf").or_else(|| schema.get("anyOf")) {
+            let option = self.pick(options).clone();
+            return self.value(&option, depth);
+        }
+
+        let kind = match schema.get("type") {
+            Some(Value::String(kind)) => kind.clone(),
+            Some(Value::Array(kinds)) => self.pick(kinds).as_str().unwrap_or("null").to_string(),
+            None if schema.contains_key("properties") => "object".to_string(),
+            None => return self.any(),
+            Some(_) => return Value::Null,
+        };
+        let minimum = schema.get("minimum").and_then(Value::as_f64);
+
+        match kind.as_str() {
+            "boolean" => Value::Bool(self.chance(0.5)),
+            "integer" => {
+                let n = *self.pick(&[
+                    0,
+                    1,
+                    -1,
+                    i64::from(i32::MAX),
+                    i64::from(i32::MIN),
+                    i64::MAX,
+                    i64::MIN,
+                ]);
+                let n = if self.chance(0.5) {
+                    self.rng.random_range(-1000..1000)
+                } else {
+                    n
+                };
+                Value::from(minimum.map_or(n, |min| n.max(min as i64)))
+            }
+            "number" => {
+                let n = *self.pick(&[0.0, -0.0, 1.5, -1.5, 1e-300, f64::MAX, f64::MIN, -86400.0]);
+                let n = if self.chance(0.5) {
+                    self.rng.random_range(-1000.0..1000.0)
+                } else {
+                    n
+                };
+                Number::from_f64(minimum.map_or(n, |min| n.max(min)))
+                    .map_or(Value::Null, Value::Number)
+            }
+            "string" => self.string(),
+            "array" => self.array(schema, depth),
+            "object" => self.object(schema, depth),
+            _ => Value::Null,
+        }
+    }
+
+    fn any(&mut self) -> Value {
+        match self.rng.random_range(0..4) {
+            0 => Value::Null,
+            1 => Value::Bool(self.chance(0.5)),
+            2 => Value::from(self.rng.random_range(-1000..1000)),
+            _ => self.string(),
+        }
+    }
+
+    fn string(&mut self) -> Value {
+        if !self.huge && self.chance(0.02) {
+            self.huge = true;
+            return Value::String("🎵a".repeat(HUGE_LEN * 8));
+        }
+        if self.chance(0.5) {
+            return Value::String(self.pick(ODD_STRINGS).to_string());
+        }
+        let len = self.rng.random_range(1..24);
+        Value::String((0..len).map(|_| self.rng.random_range(' '..='~')).collect())
+    }
+
+    fn array(&mut self, schema: &Map<String, Value>, depth: usize) -> Value {
+        if let Some(Value::Array(prefix)) = schema.get("prefixItems") {
+            return Value::Array(prefix.iter().map(|s| self.value(s, depth + 1)).collect());
+        }
+
+        let bound = |name: &str| schema.get(name).and_then(Value::as_u64).map(|n| n as usize);
+        let min = bound("minItems").unwrap_or_default();
+        let max = bound("maxItems");
+
+        let len = if depth >= MAX_DEPTH {
+            min
+        } else if !self.huge && max.is_none() && self.chance(0.02) {
+            self.huge = true;
+            HUGE_LEN
+        } else {
+            self.rng.random_range(min..=max.unwrap_or(min + 4).max(min))
+        };
+
+        let items = schema.get("items").cloned().unwrap_or(Value::Bool(true));
+        Value::Array((0..len).map(|_| self.value(&items, depth + 1)).collect())
+    }
+
+    fn object(&mut self, schema: &Map<String, Value>, depth: usize) -> Value {
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|r| r.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let mut object = Map::new();
+        if let Some(Value::Object(properties)) = schema.get("properties") {
+            for (name, property) in properties {
+                let include =
+                    required.contains(&name.as_str()) || (depth < MAX_DEPTH && self.chance(0.5));
+                if include {
+                    object.insert(name.clone(), self.value(property, depth + 1));
+                }
+            }
+        } else if depth < MAX_DEPTH
+            && schema.get("additionalProperties") != Some(&Value::Bool(false))
+        {
+            for i in 0..self.rng.random_range(0..3) {
+                object.insert(format!("key{}", i), self.any());
+            }
+        }
+        Value::Object(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::events::{EXTENSION_COMMANDS, EXTENSION_EXTRA_EVENTS};
+
+    fn generator(seed: u64) -> Generator {
+        Generator::new(StdRng::seed_from_u64(seed))
+    }
+
+    fn pointers() -> Vec<String> {
+        EXTENSION_EXTRA_EVENTS
+            .iter()
+            .map(|kind| format!("/$defs/ExtensionExtraEvent/{}", kind))
+            .chain(
+                EXTENSION_COMMANDS
+                    .iter()
+                    .map(|kind| format!("/$defs/ExtensionCommand/{}", kind)),
+            )
+            .collect()
+    }
+
+    /// Huge arrays and strings in `value`.
+    fn huge(value: &Value) -> usize {
+        match value {
+            Value::Array(items) => {
+                usize::from(items.len() == HUGE_LEN) + items.iter().map(huge).sum::<usize>()
+            }
+            Value::Object(map) => map.values().map(huge).sum(),
+            Value::String(s) => usize::from(s.chars().count() >= HUGE_LEN),
+            _ => 0,
+        }
+    }
+
+    fn depth(value: &Value) -> usize {
+        match value {
+            Value::Array(items) => 1 + items.iter().map(depth).max().unwrap_or_default(),
+            Value::Object(map) => 1 + map.values().map(depth).max().unwrap_or_default(),
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn the_same_seed_generates_the_same_payloads() {
+        let (mut first, mut second) = (generator(7), generator(7));
+        for pointer in pointers() {
+            assert_eq!(first.data(&pointer), second.data(&pointer), "{}", pointer);
+        }
+    }
+
+    #[test]
+    fn generates_every_command_and_request() {
+        let mut generator = generator(1);
+        for pointer in pointers() {
+            // The only event without a payload
+            let without = pointer.ends_with("/requestedRecommendations");
+            assert_eq!(generator.data(&pointer).is_none(), without, "{}", pointer);
+        }
+        assert!(generator.data("/$defs/ExtensionCommand/unknown").is_none());
+
+        let kinds = generator.request_kinds();
+        assert!(kinds.iter().any(|kind| kind == "getVolume"));
+        for kind in kinds {
+            let pointer = format!("/$defs/MainCommandParsable/{}", kind);
+            assert!(generator.data(&pointer).is_some(), "{}", pointer);
+        }
+    }
+
+    #[test]
+    fn follows_the_schema_of_the_payload() {
+        let mut generator = generator(3);
+        for _ in 0..50 {
+            let seeked = generator.data("/$defs/ExtensionExtraEvent/seeked").unwrap();
+            let items = seeked.as_array().unwrap();
+            assert_eq!(items.len(), 1);
+            assert!(items[0].is_i64());
+
+            let search = generator
+                .data("/$defs/ExtensionExtraEvent/requestedSearchResult")
+                .unwrap();
+            assert!(search[0].is_string());
+        }
+    }
+
+    #[test]
+    fn payloads_have_one_huge_value_at_most_and_stay_shallow() {
+        let mut generator = generator(11);
+        let mut any_huge = false;
+        for _ in 0..20 {
+            for pointer in pointers() {
+                let Some(data) = generator.data(&pointer) else {
+                    continue;
+                };
+                assert!(huge(&data) <= 1, "{}: {}", pointer, data);
+                // Required properties may nest a few levels past the limit
+                assert!(depth(&data) <= MAX_DEPTH + 4, "{}: {}", pointer, data);
+                any_huge |= huge(&data) == 1;
+            }
+        }
+        assert!(any_huge);
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    mem,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde_json::Value;
+use types::errors::{MoosyncError, Result};
+
+use crate::{HostCall, HostResponder, mocks::MockSet};
+
+/// A simulated host response time, from the `delayMs` of a mock or `--host-latency`.
+/// Either fixed, or picked uniformly from `min..=max` for every request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Latency {
+    min_ms: u64,
+    max_ms: u64,
+    /// Seed for picking random delays. Picked from the OS if unset.
+    seed: Option<u64>,
+}
+
+impl Latency {
+    fn new(min_ms: u64, max_ms: u64, seed: Option<u64>) -> Result<Self> {
+        if min_ms > max_ms {
+            return Err(format!("Invalid latency range: {} > {}", min_ms, max_ms).into());
+        }
+        Ok(Self {
+            min_ms,
+            max_ms,
+            seed,
+        })
+    }
+
+    pub(crate) fn with_seed(self, seed: Option<u64>) -> Self {
+        Self { seed, ..self }
+    }
+
+    /// Parses `delayMs`, either a number of milliseconds or `{ min, max, seed }`.
+    pub(crate) fn from_value(value: &Value) -> Result<Self> {
+        if let Some(ms) = value.as_u64() {
+            return Self::new(ms, ms, None);
+        }
+
+        let range = value.as_object().ok_or_else(|| {
+            MoosyncError::String("delayMs must be a number or { min, max, seed }".into())
+        })?;
+        let field = |name: &str| range.get(name).and_then(|v| v.as_u64());
+        match (field("min"), field("max")) {
+            (Some(min), Some(max)) => Self::new(min, max, field("seed")),
+            _ => Err("delayMs range must have numeric min and max".into()),
+        }
+    }
+}
+
+/// Parses `--host-latency`, either `MS` or `MIN-MAX`.
+impl FromStr for Latency {
+    type Err = MoosyncError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = |ms: &str| {
+            ms.trim()
+                .parse::<u64>()
+                .map_err(|_| MoosyncError::String(format!("Invalid latency: {}", s)))
+        };
+        match s.split_once('-') {
+            Some((min, max)) => Self::new(parse(min)?, parse(max)?, None),
+            None => {
+                let ms = parse(s)?;
+                Self::new(ms, ms, None)
+            }
+        }
+    }
+}
+
+/// Picks the delays of a [`Latency`]. Created for every test run so seeded runs are
+/// reproducible.
+pub(crate) struct Delay {
+    latency: Latency,
+    rng: Mutex<StdRng>,
+}
+
+impl Delay {
+    pub(crate) fn new(latency: Latency) -> Self {
+        let rng = match latency.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        Self {
+            latency,
+            rng: Mutex::new(rng),
+        }
+    }
+
+    pub(crate) fn sample(&self) -> Duration {
+        let Latency { min_ms, max_ms, .. } = self.latency;
+        let ms = if min_ms == max_ms {
+            min_ms
+        } else {
+            self.rng.lock().unwrap().random_range(min_ms..=max_ms)
+        };
+        Duration::from_millis(ms)
+    }
+}
+
+/// Everything used to answer the host requests of an extension, and the requests
+/// answered so far.
+pub(crate) struct Host {
+    pub(crate) mocks: RwLock<MockSet>,
+    pub(crate) responder: Option<Arc<dyn HostResponder>>,
+    /// Delay applied to requests whose mock has no `delayMs`
+    pub(crate) latency: Option<Delay>,
+    pub(crate) print: bool,
+    calls: Mutex<Vec<HostCall>>,
+}
+
+impl Host {
+    pub(crate) fn new(
+        mocks: MockSet,
+        responder: Option<Arc<dyn HostResponder>>,
+        latency: Option<Latency>,
+        print: bool,
+    ) -> Self {
+        Self {
+            mocks: RwLock::new(mocks),
+            responder,
+            latency: latency.map(Delay::new),
+            print,
+            calls: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn record(&self, call: HostCall) {
+        self.calls.lock().unwrap().push(call);
+    }
+
+    /// Returns the requests answered since the last call.
+    pub(crate) fn take_calls(&self) -> Vec<HostCall> {
+        mem::take(&mut *self.calls.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn latency_parses_fixed_and_ranged_delays() {
+        assert_eq!(
+            "250".parse::<Latency>().unwrap(),
+            Latency::new(250, 250, None).unwrap()
+        );
+        assert_eq!(
+            "50-200".parse::<Latency>().unwrap(),
+            Latency::new(50, 200, None).unwrap()
+        );
+        assert_eq!(
+            " 50 - 200 ".parse::<Latency>().unwrap(),
+            Latency::new(50, 200, None).unwrap()
+        );
+    }
+
+    #[test]
+    fn latency_rejects_invalid_delays() {
+        for invalid in ["", "fast", "-5", "10-", "200-50", "1.5"] {
+            assert!(invalid.parse::<Latency>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn latency_reads_delay_ms() {
+        assert_eq!(
+            Latency::from_value(&json!(100)).unwrap(),
+            Latency::new(100, 100, None).unwrap()
+        );
+        assert_eq!(
+            Latency::from_value(&json!({ "min": 10, "max": 20, "seed": 7 })).unwrap(),
+            Latency::new(10, 20, Some(7)).unwrap()
+        );
+        assert!(Latency::from_value(&json!({ "min": 10 })).is_err());
+        assert!(Latency::from_value(&json!({ "min": 30, "max": 20 })).is_err());
+        assert!(Latency::from_value(&json!("100")).is_err());
+    }
+
+    #[test]
+    fn seeded_delays_are_reproducible_and_in_range() {
+        let latency = Latency::new(10, 1000, Some(42)).unwrap();
+        let first: Vec<_> = (0..20)
+            .map(|_| Delay::new(latency))
+            .map(|d| d.sample())
+            .collect();
+        let delay = Delay::new(latency);
+        let samples: Vec<_> = (0..20).map(|_| delay.sample()).collect();
+        assert!(first.iter().all(|d| *d == first[0]));
+        assert_eq!(samples[0], first[0]);
+        assert!(
+            samples
+                .iter()
+                .all(|d| (10..=1000).contains(&(d.as_millis() as u64)))
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap,
+    io::{IsTerminal, stdin},
+    path::Path,
+};
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use types::errors::{MoosyncError, Result};
+
+use crate::{
+    CommandWrapper,
+    format::{TraceCodec, TraceFormat},
+};
+
+/// Prefix of environment variables that provide data for interactive commands.
+/// The rest of the variable name is the upper-cased input key, e.g. `MOODRIVER_INPUT_OAUTH_CODE`.
+const ENV_PREFIX: &str = "MOODRIVER_INPUT_";
+
+/// Describes how the data of an interactive command is requested.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub(crate) struct Prompt {
+    /// Key used to look the value up in `--input`, the inputs file and the environment
+    key: Option<String>,
+    /// Text shown when asking for the value on stdin
+    label: Option<String>,
+}
+
+/// Values supplied for interactive commands, so traces using them can run without a terminal.
+#[derive(Debug, Default)]
+pub(crate) struct InteractiveInputs {
+    cli: HashMap<String, String>,
+    file: Map<String, Value>,
+}
+
+impl InteractiveInputs {
+    pub(crate) fn new(cli_inputs: &[String], inputs_file: Option<&Path>) -> Result<Self> {
+        let mut cli = HashMap::new();
+        for input in cli_inputs {
+            let (key, value) = input.split_once('=').ok_or_else(|| {
+                MoosyncError::String(format!("Invalid input '{}', expected key=value", input))
+            })?;
+            cli.insert(key.to_string(), value.to_string());
+        }
+
+        let file = match inputs_file {
+            Some(path) => {
+                let format = TraceFormat::from_path(path).ok_or_else(|| {
+                    MoosyncError::String(format!("Unsupported inputs file extension: {:?}", path))
+                })?;
+                match format.parse(path)? {
+                    Value::Object(map) => map,
+                    _ => return Err(format!("Inputs file {:?} must be an object", path).into()),
+                }
+            }
+            None => Map::new(),
+        };
+
+        Ok(Self { cli, file })
+    }
+
+    fn lookup(&self, key: &str, template: &Value) -> Option<Value> {
+        if let Some(raw) = self.cli.get(key) {
+            return Some(parse_raw(raw, template));
+        }
+
+        if let Some(value) = self.file.get(key) {
+            return Some(value.clone());
+        }
+
+        std::env::var(env_var_name(key))
+            .ok()
+            .map(|raw| parse_raw(&raw, template))
+    }
+
+    /// Fills in the `data` of an interactive command.
+    ///
+    /// The value is taken from `--input`, the inputs file or the environment, and only
+    /// read from stdin as a last resort when it is a terminal.
+    pub(crate) fn resolve(&self, command: &mut CommandWrapper) -> Result<()> {
+        if !command.interactive {
+            return Ok(());
+        }
+
+        let mut value = serde_json::to_value(command.command.clone())?;
+        let template = value.get("data").cloned().unwrap_or(Value::Null);
+        let prompt = command.prompt.clone().unwrap_or_default();
+        let key = prompt
+            .key
+            .or_else(|| command.name.clone())
+            .or_else(|| value.get("type").and_then(|t| t.as_str()).map(String::from))
+            .unwrap_or_default();
+
+        if let Some(data) = self.lookup(&key, &template) {
+            let data = conform(data, &template);
+            validate(&data, &template)
+                .map_err(|e| MoosyncError::String(format!("Invalid input '{}': {}", key, e)))?;
+            value["data"] = data;
+            command.command = serde_json::from_value(value).map_err(|e| {
+                MoosyncError::String(format!("Could not parse input '{}': {}", key, e))
+            })?;
+            return Ok(());
+        }
+
+        if !stdin().is_terminal() {
+            return Err(format!(
+                "No input provided for interactive command '{}'. Pass --input {}=<value>, \
+                 an inputs file or set {}",
+                key,
+                key,
+                env_var_name(&key)
+            )
+            .into());
+        }
+
+        let label = prompt.label.unwrap_or(key);
+        loop {
+            println!(
+                "Enter data for {} ({}) > ",
+                label,
+                describe_shape(&template)
+            );
+            let mut buffer = String::new();
+            let read = stdin().read_line(&mut buffer).map_err(|e| {
+                MoosyncError::String(format!("Failed to read data for {}: {}", label, e))
+            })?;
+            if read == 0 {
+                return Err(format!("Stdin closed before data for {} was entered", label).into());
+            }
+
+            let data = conform(parse_raw(buffer.trim(), &template), &template);
+            if let Err(e) = validate(&data, &template) {
+                println!("Invalid data: {}, try again...", e);
+                continue;
+            }
+
+            value["data"] = data;
+            match serde_json::from_value(value.clone()) {
+                Ok(val) => {
+                    command.command = val;
+                    return Ok(());
+                }
+                Err(e) => {
+                    println!("Could not parse data: {}, {}, try again...", buffer, e);
+                }
+            }
+        }
+    }
+}
+
+fn env_var_name(key: &str) -> String {
+    let key = key
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!("{}{}", ENV_PREFIX, key)
+}
+
+/// Parses a raw input by the type of its placeholder. Inputs for a string are taken as is,
+/// so `--input code=12345` stays a string, while others are parsed as JSON and fall back to
+/// a plain string.
+fn parse_raw(raw: &str, template: &Value) -> Value {
+    // A single value for a one element tuple is wrapped by `conform` afterwards
+    let expected = match template {
+        Value::Array(items) if items.len() == 1 && !raw.trim_start().starts_with('[') => &items[0],
+        other => other,
+    };
+    if expected.is_string() {
+        return Value::String(raw.to_string());
+    }
+    serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()))
+}
+
+/// Wraps a single value in an array when the command expects a one element tuple,
+/// so `--input seeked=30` works for `"data": [0]`.
+fn conform(data: Value, template: &Value) -> Value {
+    match template {
+        Value::Array(items) if items.len() == 1 && !data.is_array() => Value::Array(vec![data]),
+        _ => data,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "any",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn describe_shape(template: &Value) -> String {
+    match template {
+        Value::Array(items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .map(describe_shape)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Value::Object(map) => format!(
+            "{{{}}}",
+            map.iter()
+                .map(|(k, v)| format!("{}: {}", k, describe_shape(v)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        other => type_name(other).to_string(),
+    }
+}
+
+/// Checks that `data` has the same shape as the placeholder `data` of the command.
+/// A `null` in the placeholder accepts any value.
+fn validate(data: &Value, template: &Value) -> std::result::Result<(), String> {
+    match (template, data) {
+        (Value::Null, _) => Ok(()),
+        (Value::Bool(_), Value::Bool(_)) | (Value::String(_), Value::String(_)) => Ok(()),
+        (Value::Number(_), Value::Number(_)) => Ok(()),
+        (Value::Array(expected), Value::Array(received)) => {
+            if expected.len() != received.len() {
+                return Err(format!(
+                    "expected {} items, received {}",
+                    expected.len(),
+                    received.len()
+                ));
+            }
+            expected
+                .iter()
+                .zip(received)
+                .try_for_each(|(t, d)| validate(d, t))
+        }
+        (Value::Object(expected), Value::Object(received)) => {
+            expected
+                .iter()
+                .try_for_each(|(k, t)| match received.get(k) {
+                    Some(d) => validate(d, t).map_err(|e| format!("{}: {}", k, e)),
+                    None => Err(format!("missing field '{}'", k)),
+                })
+        }
+        _ => Err(format!(
+            "expected {}, received {}",
+            describe_shape(template),
+            type_name(data)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parse_raw_keeps_strings_as_is() {
+        assert_eq!(parse_raw("12345", &json!("")), json!("12345"));
+        assert_eq!(parse_raw("12345", &json!([""])), json!("12345"));
+        assert_eq!(parse_raw("12345", &json!(0)), json!(12345));
+        assert_eq!(parse_raw("not json", &json!(0)), json!("not json"));
+    }
+
+    #[test]
+    fn conform_wraps_single_values_of_one_element_tuples() {
+        assert_eq!(conform(json!(30), &json!([0])), json!([30]));
+        assert_eq!(conform(json!([30]), &json!([0])), json!([30]));
+        assert_eq!(conform(json!(30), &json!(0)), json!(30));
+    }
+
+    #[test]
+    fn validate_checks_the_shape_of_the_placeholder() {
+        let template = json!({ "code": "", "retries": [0, true] });
+        assert!(
+            validate(
+                &json!({ "code": "x", "retries": [3, false], "extra": 1 }),
+                &template
+            )
+            .is_ok()
+        );
+        assert!(validate(&json!({ "code": "x" }), &template).is_err());
+        assert!(validate(&json!({ "code": 1, "retries": [3, false] }), &template).is_err());
+        assert!(validate(&json!({ "code": "x", "retries": [3] }), &template).is_err());
+        assert!(validate(&json!({ "anything": [] }), &Value::Null).is_ok());
+    }
+
+    #[test]
+    fn env_var_name_upper_cases_the_key() {
+        assert_eq!(env_var_name("oauth-code"), "MOODRIVER_INPUT_OAUTH_CODE");
+    }
+}
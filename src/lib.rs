@@ -0,0 +1,1338 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use assertions::{HostCallAssertion, check_host_calls};
+use colored::*;
+use coverage::WasmCoverage;
+use expect::ErrorMatcher;
+use extensions::{ExtensionHandler, models::ExtensionCommand};
+use filter::{Selection, command_label};
+use fixtures::{FIXTURES_DIR, resolve_file_refs};
+use format::{TraceCodec, TraceFormat, is_trace_file};
+use futures::future::join_all;
+use host::{Delay, Host, Latency};
+use inputs::{InteractiveInputs, Prompt};
+use manifest::{extension_entry, mirror_with_entry};
+use mocks::{MockSet, command_kind};
+use probe::{Calls, Probe, Reports, Usage};
+use resources::{check_limits, mb_to_pages};
+use serde::{Deserialize, Deserializer, Serialize, de};
+use serde_json::{Map, Value};
+use tempdir::TempDir;
+use types::{
+    errors::Result,
+    songs::Song,
+    ui::{
+        extensions::{ExtensionExtraEvent, ExtensionExtraEventArgs, PreferenceData},
+        player_details::PlayerState,
+    },
+};
+use ui::finish_and_clear;
+use utils::{
+    expand_matrix, pretty_print_diff, remove_nulls, sanitize_resp_by_expected, substitute_vars,
+};
+use walkdir::WalkDir;
+use wasm::{CodeMap, Instrumentation, Meter};
+
+#[doc(hidden)]
+pub use driver::__private;
+pub use driver::Driver;
+pub use moodriver_macros::trace_test;
+pub use probe::ProbeLayer;
+pub use report::{
+    AnsweredBy, CommandReport, CommandStatus, HostCall, MockCoverage, MockUsage, Phase, TraceReport,
+};
+pub use resources::MemoryUsage;
+pub use responder::{HostResponder, ResponderRegistry};
+pub use types::{
+    errors::MoosyncError,
+    extensions::{MainCommand, MainCommandResponse},
+};
+
+mod assertions;
+mod bench;
+pub mod cli;
+mod coverage;
+mod driver;
+mod events;
+mod expect;
+mod filter;
+mod fixtures;
+mod format;
+mod fuzz;
+mod generate;
+mod host;
+mod inputs;
+mod manifest;
+mod matcher;
+mod mocks;
+mod probe;
+mod repl;
+mod replay;
+mod report;
+mod resources;
+mod responder;
+mod soak;
+mod tempdir;
+mod tracing;
+mod ui;
+mod utils;
+mod wasm;
+mod watch;
+
+/// Prints to stdout, unless the run only collects reports.
+macro_rules! report {
+    ($print:expr, $($arg:tt)*) => {
+        if $print {
+            println!($($arg)*);
+        }
+    };
+}
+
+type ReplyHandler =
+    Arc<Box<dyn Fn(&str, MainCommand) -> Result<MainCommandResponse> + Sync + Send>>;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub(crate) enum ValidCommand {
+    ExtensionExtraEvent(ExtensionExtraEvent),
+    ExtensionCommand(ExtensionCommand),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct CommandWrapper {
+    #[serde(flatten)]
+    command: ValidCommand,
+    expected: Option<Value>,
+    #[serde(rename = "expectedError")]
+    expected_error: Option<ErrorMatcher>,
+    #[serde(default)]
+    interactive: bool,
+    name: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    skip: bool,
+    #[serde(default)]
+    only: bool,
+    prompt: Option<Prompt>,
+    #[serde(rename = "maxMemoryMb")]
+    max_memory_mb: Option<f64>,
+    #[serde(rename = "maxFuel")]
+    max_fuel: Option<u64>,
+    #[serde(skip)]
+    parallel: Option<Parallel>,
+}
+
+/// Default time a command of a parallel group may take before the extension is
+/// considered deadlocked.
+const DEFAULT_PARALLEL_TIMEOUT_MS: u64 = 10_000;
+
+/// A group of commands that are sent concurrently, written as `{ "parallel": [...] }`
+/// in place of a command.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct ParallelGroup {
+    parallel: Vec<CommandWrapper>,
+    timeout_ms: Option<u64>,
+}
+
+/// The parallel group a command belongs to.
+#[derive(Debug, Clone, Copy)]
+struct Parallel {
+    id: usize,
+    /// Time a command may take before the extension is considered deadlocked
+    timeout: Duration,
+}
+
+/// Deserializes a list of commands, flattening parallel groups into their commands.
+fn deserialize_commands<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Vec<CommandWrapper>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut commands = Vec::new();
+    for (id, step) in Vec::<Value>::deserialize(deserializer)?
+        .into_iter()
+        .enumerate()
+    {
+        if step.get("parallel").is_none() {
+            commands.push(serde_json::from_value(step).map_err(de::Error::custom)?);
+            continue;
+        }
+
+        let group: ParallelGroup = serde_json::from_value(step).map_err(de::Error::custom)?;
+        let parallel = Parallel {
+            id,
+            timeout: Duration::from_millis(group.timeout_ms.unwrap_or(DEFAULT_PARALLEL_TIMEOUT_MS)),
+        };
+        commands.extend(group.parallel.into_iter().map(|mut command| {
+            command.parallel = Some(parallel);
+            command
+        }));
+    }
+    Ok(commands)
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase", tag = "type", content = "data")]
+pub(crate) enum MainCommandParsable {
+    GetSong(Vec<Song>),
+    GetEntity(Value),
+    GetCurrentSong(Option<Song>),
+    GetPlayerState(PlayerState),
+    GetVolume(f64),
+    GetTime(f64),
+    GetQueue(Value),
+    GetPreference(PreferenceData),
+    SetPreference(bool),
+    GetSecure(PreferenceData),
+    SetSecure(bool),
+    AddSongs(Vec<Song>),
+    RemoveSong(bool),
+    UpdateSong(Song),
+    AddPlaylist(String),
+    AddToPlaylist(bool),
+    RegisterOAuth(bool),
+    OpenExternalUrl(bool),
+    UpdateAccounts(bool),
+    ExtensionsUpdated(bool),
+    RegisterUserPreference(bool),
+    UnregisterUserPreference(bool),
+    GetAppVersion(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct TestCase {
+    name: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_commands")]
+    setup: Vec<CommandWrapper>,
+    #[serde(deserialize_with = "deserialize_commands")]
+    commands: Vec<CommandWrapper>,
+    #[serde(default, deserialize_with = "deserialize_commands")]
+    teardown: Vec<CommandWrapper>,
+    requests: Vec<Value>,
+    #[serde(default, rename = "hostCallAssertions")]
+    host_call_assertions: Vec<Value>,
+    /// Memory limit of commands without their own `maxMemoryMb`
+    #[serde(rename = "maxMemoryMb")]
+    max_memory_mb: Option<f64>,
+    /// Instruction limit of commands without their own `maxFuel`
+    #[serde(rename = "maxFuel")]
+    max_fuel: Option<u64>,
+}
+
+impl TestCase {
+    /// Metering that stops the extension at the highest limits of the trace and its
+    /// commands, if any are set. Lower limits of single commands are checked once they
+    /// finished.
+    fn meter(&self) -> Option<Meter> {
+        let commands = || {
+            self.setup
+                .iter()
+                .chain(&self.commands)
+                .chain(&self.teardown)
+        };
+        let meter = Meter {
+            max_fuel: commands()
+                .filter_map(|c| c.max_fuel)
+                .chain(self.max_fuel)
+                .max(),
+            max_pages: commands()
+                .filter_map(|c| c.max_memory_mb)
+                .chain(self.max_memory_mb)
+                .reduce(f64::max)
+                .map(mb_to_pages),
+        };
+        (meter.max_fuel.is_some() || meter.max_pages.is_some()).then_some(meter)
+    }
+}
+
+/// A named set of variable bindings used to expand a single trace into multiple runs.
+#[derive(Debug, Deserialize, Clone)]
+struct TraceCase {
+    name: String,
+    #[serde(default)]
+    vars: Map<String, Value>,
+}
+
+/// A single run of a trace file, after its cases have been expanded.
+#[derive(Debug)]
+struct TestRun {
+    name: String,
+    test_case: TestCase,
+    /// Variables of the case, available to scripted mocks
+    vars: Map<String, Value>,
+    /// Directory of the trace file
+    dir: PathBuf,
+}
+
+fn setup_ext_handler(
+    ext_dir: PathBuf,
+    dirs: &TempDir,
+    reply_handler: ReplyHandler,
+) -> Result<ExtensionHandler> {
+    let handler = ExtensionHandler::new(
+        ext_dir,
+        dirs.path().join("tmp"),
+        dirs.path().join("cache"),
+        reply_handler,
+    );
+
+    Ok(handler)
+}
+
+fn read_trace(test_file: &Path) -> Result<Value> {
+    let format = TraceFormat::from_path(test_file).ok_or_else(|| {
+        MoosyncError::String(format!("Unsupported trace file extension: {:?}", test_file))
+    })?;
+
+    format.parse(test_file)
+}
+
+/// Parses a trace file and expands its `cases` and `matrix` sections into separate runs.
+/// A trace without either section produces a single run named after the file.
+fn parse_test_case(test_file: &Path) -> Result<Vec<TestRun>> {
+    let mut trace = read_trace(test_file)?;
+    let dir = test_file.parent().unwrap_or(Path::new("."));
+    resolve_file_refs(&mut trace, dir)?;
+
+    let root = trace
+        .as_object_mut()
+        .ok_or_else(|| MoosyncError::String("Trace must be an object".into()))?;
+
+    let base_vars: Map<String, Value> = match root.remove("vars") {
+        Some(vars) => serde_json::from_value(vars)?,
+        None => Map::new(),
+    };
+
+    let mut cases: Vec<TraceCase> = match root.remove("cases") {
+        Some(cases) => serde_json::from_value(cases)?,
+        None => Vec::new(),
+    };
+
+    if let Some(matrix) = root.remove("matrix") {
+        let matrix: Map<String, Value> = serde_json::from_value(matrix)?;
+        for vars in expand_matrix(&matrix)? {
+            let name = vars
+                .iter()
+                .map(|(k, v)| match v {
+                    Value::String(s) => format!("{}={}", k, s),
+                    other => format!("{}={}", k, other),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            cases.push(TraceCase { name, vars });
+        }
+    }
+
+    let file_name = test_file.to_string_lossy().to_string();
+    if cases.is_empty() {
+        cases.push(TraceCase {
+            name: String::new(),
+            vars: Map::new(),
+        });
+    }
+
+    let mut runs = Vec::with_capacity(cases.len());
+    for case in cases {
+        let mut vars = base_vars.clone();
+        vars.extend(case.vars);
+
+        let mut value = trace.clone();
+        substitute_vars(&mut value, &vars);
+
+        let test_case: TestCase = serde_json::from_value(value)?;
+        let trace_name = test_case.name.as_ref().unwrap_or(&file_name);
+        let name = if case.name.is_empty() {
+            trace_name.clone()
+        } else {
+            format!("{} [{}]", trace_name, case.name)
+        };
+
+        runs.push(TestRun {
+            name,
+            test_case,
+            vars,
+            dir: dir.to_path_buf(),
+        });
+    }
+
+    Ok(runs)
+}
+
+macro_rules! define_command_mappings {
+    (
+        with_params: [$($with_params:ident),* $(,)?],
+        no_params: [$($no_params:ident),* $(,)?],
+        preference_commands: [$($pref_command:ident),* $(,)?]
+    ) => {
+        fn create_response_from_request(request: &MainCommandParsable) -> MainCommandResponse {
+            match request {
+                $(
+                    MainCommandParsable::$with_params(data) => MainCommandResponse::$with_params(data.clone()),
+                )*
+                $(
+                    MainCommandParsable::$no_params(data) => MainCommandResponse::$no_params(data.clone()),
+                )*
+                $(
+                    MainCommandParsable::$pref_command(data) => MainCommandResponse::$pref_command(data.clone()),
+                )*
+            }
+        }
+
+        fn create_default_response(command: &MainCommand) -> MainCommandResponse {
+            match command {
+                $(
+                    MainCommand::$with_params(_) => MainCommandResponse::$with_params(Default::default()),
+                )*
+                $(
+                    MainCommand::$no_params() => MainCommandResponse::$no_params(Default::default()),
+                )*
+                $(
+                    MainCommand::$pref_command(_) => MainCommandResponse::$pref_command(Default::default()),
+                )*
+            }
+        }
+    };
+}
+
+define_command_mappings!(
+    with_params: [
+        GetSong, GetEntity, SetPreference, SetSecure,
+        AddSongs, RemoveSong, UpdateSong, AddPlaylist, AddToPlaylist, RegisterOAuth,
+        OpenExternalUrl, UpdateAccounts, RegisterUserPreference, UnregisterUserPreference
+    ],
+
+    no_params: [
+        GetCurrentSong, GetPlayerState, GetVolume, GetTime, GetQueue, ExtensionsUpdated, GetAppVersion
+    ],
+
+    preference_commands: [
+        GetPreference, GetSecure
+    ]
+);
+
+async fn handle_ui_requests(
+    package_name: &str,
+    command: MainCommand,
+    host: &Host,
+) -> Result<MainCommandResponse> {
+    let started = Instant::now();
+    let kind = command_kind(&command)?;
+    let data = serde_json::to_value(&command)?
+        .get_mut("data")
+        .map(Value::take)
+        .unwrap_or_default();
+
+    let request_description = match &command {
+        MainCommand::GetPreference(pref) => {
+            format!(
+                "GetPreference with key 'extensions.{}.{}'",
+                package_name, pref.key
+            )
+        }
+        MainCommand::GetSecure(pref) => {
+            format!(
+                "GetSecure with key 'extensions.{}.{}'",
+                package_name, pref.key
+            )
+        }
+        other => format!("{:?}", other),
+    };
+
+    let (response, delay, answered_by) = match host
+        .responder
+        .as_ref()
+        .and_then(|r| r.respond(package_name, &command))
+    {
+        Some(response) => (response, None, AnsweredBy::Responder),
+        None => match host
+            .mocks
+            .read()
+            .unwrap()
+            .respond(package_name, &command, &kind, &data)
+        {
+            Some(reply) => (reply.response, reply.delay, AnsweredBy::Mock(reply.index)),
+            None => (
+                Ok(create_default_response(&command)),
+                None,
+                AnsweredBy::Default,
+            ),
+        },
+    };
+
+    let delay = delay
+        .or_else(|| host.latency.as_ref().map(Delay::sample))
+        .unwrap_or_default();
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+
+    host.record(HostCall {
+        kind,
+        data,
+        delay_ms: delay.as_secs_f64() * 1000.0,
+        duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+        error: response.as_ref().err().map(|e| e.to_string()),
+        answered_by,
+    });
+
+    if !host.print {
+        return response;
+    }
+
+    let response_value = match &response {
+        Ok(MainCommandResponse::GetPreference(data)) => {
+            format!(
+                "data for key 'extensions.{}.{}': '{:?}'",
+                package_name, data.key, data.value
+            )
+        }
+        Ok(MainCommandResponse::GetSecure(data)) => {
+            format!(
+                "data for key 'extensions.{}.{}': '{:?}'",
+                package_name, data.key, data.value
+            )
+        }
+        Ok(other) => format!("{:?}", other),
+        Err(e) => format!("error '{}'", e),
+    };
+    let request_description = if delay.is_zero() {
+        request_description
+    } else {
+        format!("{} (after {}ms)", request_description, delay.as_millis())
+    };
+
+    ui::log_ui_request(&request_description, &response_value).await;
+
+    response
+}
+
+/// Answers host requests from the responder, if any, and then the mocks of a trace.
+fn reply_handler(host: Arc<Host>) -> ReplyHandler {
+    let runtime = tokio::runtime::Handle::try_current().unwrap();
+    Arc::new(Box::new(move |package_name, command| {
+        runtime.block_on(handle_ui_requests(package_name, command, &host))
+    }))
+}
+
+/// A loaded extension.
+struct Extension {
+    handler: ExtensionHandler,
+    package_name: String,
+    /// Receives the reports of the wasm module, if it was instrumented
+    probe: Option<Probe>,
+    /// Code of the wasm module, if it was instrumented
+    code: CodeMap,
+    /// Working directories of the handler, removed once the extension is dropped
+    _dirs: TempDir,
+}
+
+impl Extension {
+    /// What the instrumented wasm module reported so far.
+    fn reports(&self) -> Result<MutexGuard<'_, Reports>> {
+        let reports = self
+            .probe
+            .as_ref()
+            .ok_or("The extension was loaded without instrumentation")?
+            .reports();
+        if !reports.received {
+            return Err("The instrumented extension reported nothing".into());
+        }
+        Ok(reports)
+    }
+
+    /// Resources used since the last call, if the wasm module is metered.
+    fn take_usage(&self) -> Option<Usage> {
+        self.probe
+            .as_ref()
+            .map(|probe| probe.reports().take_usage())
+    }
+
+    /// How the calls of the wasm module ended since the last call, if it is instrumented
+    /// with `calls`.
+    fn take_calls(&self) -> Option<Calls> {
+        self.probe
+            .as_ref()
+            .map(|probe| probe.reports().take_calls())
+    }
+
+    async fn send(&self, command: ValidCommand) -> Result<Value> {
+        let resp = match command {
+            ValidCommand::ExtensionExtraEvent(command) => {
+                self.handler
+                    .send_extension_command(ExtensionCommand::ExtraExtensionEvent(Box::new(
+                        ExtensionExtraEventArgs {
+                            data: command,
+                            package_name: self.package_name.clone(),
+                        },
+                    )))
+                    .await?
+            }
+            ValidCommand::ExtensionCommand(command) => {
+                self.handler.send_extension_command(command).await?
+            }
+        };
+
+        Ok(serde_json::to_value(resp)?)
+    }
+}
+
+/// How often a loading extension is checked for being active.
+const EXTENSION_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Loads the extension of a manifest. Unless `instrumentation` is empty, an instrumented
+/// copy of its wasm module is loaded instead, see `wasm`.
+async fn start_extension(
+    wasm: &Path,
+    reply_handler: ReplyHandler,
+    verbose: u8,
+    print: bool,
+    instrumentation: Instrumentation,
+) -> Result<Extension> {
+    let dirs = TempDir::new()?;
+    let probe = (!instrumentation.is_empty()).then(Probe::new);
+    let instrumented = match &probe {
+        Some(probe) => {
+            let entry = extension_entry(wasm)?;
+            let module = fs::read(&entry)
+                .map_err(|e| format!("Failed to read {}: {}", entry.display(), e))?;
+            match wasm::instrument(&module, probe.token(), instrumentation) {
+                Ok(instrumented) => Some(instrumented),
+                // Metering without limits only adds details to the reports
+                Err(e) if !instrumentation.enforced() => {
+                    report!(
+                        print,
+                        "{} {}",
+                        "Loading the extension unmetered:".yellow(),
+                        e
+                    );
+                    None
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        None => None,
+    };
+    let (ext_dir, probe, code) = match (instrumented, probe) {
+        (Some(instrumented), Some(probe)) => {
+            let ext_dir = dirs.path().join("ext");
+            mirror_with_entry(wasm, &ext_dir, &instrumented.module)?;
+            probe
+                .reports()
+                .set_initial_pages(instrumented.initial_pages);
+            probe::install(&probe)?;
+            (ext_dir, Some(probe), instrumented.code)
+        }
+        _ => (
+            wasm.parent().unwrap().to_path_buf(),
+            None,
+            CodeMap::default(),
+        ),
+    };
+    let handler = setup_ext_handler(ext_dir, &dirs, reply_handler)?;
+
+    handler.find_new_extensions().await?;
+
+    let mut is_waiting: bool = true;
+
+    if print {
+        ui::initialize_progress_bar(verbose).await;
+    }
+
+    let mut notified: HashMap<String, bool> = HashMap::new();
+    while is_waiting {
+        is_waiting = true;
+        let exts = handler.get_installed_extensions().await?;
+        let mut active = 0;
+        for ext in exts.iter() {
+            if !notified.contains_key(&ext.package_name) {
+                notified.insert(ext.package_name.clone(), true);
+                report!(
+                    print,
+                    "Extension found {}, active: {}",
+                    ext.package_name,
+                    ext.active
+                );
+            }
+            if ext.active {
+                active += 1;
+            }
+        }
+
+        if !exts.is_empty() && active == exts.len() {
+            is_waiting = false
+        } else {
+            tokio::time::sleep(EXTENSION_POLL_INTERVAL).await;
+        }
+    }
+
+    if !is_waiting {
+        finish_and_clear().await;
+    }
+
+    let package_name = handler
+        .get_installed_extensions()
+        .await?
+        .first()
+        .unwrap()
+        .package_name
+        .clone();
+
+    report!(print, "Extension active: {}", package_name.yellow());
+
+    Ok(Extension {
+        handler,
+        package_name,
+        probe,
+        code,
+        _dirs: dirs,
+    })
+}
+
+fn describe_command(command: &CommandWrapper) -> String {
+    let command_desc = match &command.command {
+        ValidCommand::ExtensionExtraEvent(event) => {
+            format!("ExtensionExtraEvent[type: {:?}]", event)
+        }
+        ValidCommand::ExtensionCommand(cmd) => format!("ExtensionCommand[{:?}]", cmd),
+    };
+    command_label(command, &command_desc)
+}
+
+/// The `type` of a command, as written in the trace.
+fn command_type(command: &ValidCommand) -> String {
+    serde_json::to_value(command)
+        .ok()
+        .and_then(|v| v.get("type").and_then(|t| t.as_str()).map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn check_response(resp: &Value, expected: Option<Value>, phase: Phase, print: bool) -> Result<()> {
+    if let Some(mut expected) = expected {
+        let mut resp_value = resp.clone();
+        let original_resp = resp_value.clone();
+        sanitize_resp_by_expected(&mut resp_value, &mut expected);
+
+        remove_nulls(&mut expected);
+        remove_nulls(&mut resp_value);
+
+        // if !is_ignore(&expected) {
+        if resp_value != expected {
+            let received_str = serde_json::to_string_pretty(&resp_value).unwrap();
+            let expected_str = serde_json::to_string_pretty(&expected).unwrap();
+
+            return Err(
+                format!("Expected response does not match received response:\n{}\n\n{}", pretty_print_diff(&expected_str, &received_str), "Legend: \n\"+\" - Present in expected but not in received\n\"-\" - Present in received but not in expected".cyan()).into(),
+            );
+        } else {
+            report!(print, "Received response {:?}", original_resp);
+        }
+    } else if phase == Phase::Test && !resp.is_null() {
+        return Err(format!(
+            "Expected: null, received: {}",
+            serde_json::to_string_pretty(&resp).unwrap()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Options shared by every trace run, from the CLI or the library API.
+struct RunOptions {
+    manifest_path: PathBuf,
+    verbose: u8,
+    selection: Selection,
+    inputs: InteractiveInputs,
+    /// Request mocks used after the ones defined by the trace
+    mocks: Vec<Value>,
+    /// Answers host requests before the mocks of the trace
+    responder: Option<Arc<dyn HostResponder>>,
+    /// Delay of host requests whose mock has no `delayMs`
+    host_latency: Option<Latency>,
+    /// Whether progress is printed to stdout. Library runs only return reports.
+    print: bool,
+    /// Whether to stop running the cases of a trace after the first failure
+    fail_fast: bool,
+    /// Collects how often the code of the wasm module ran in the tests, for
+    /// `--wasm-coverage`
+    wasm_coverage: Option<Arc<Mutex<WasmCoverage>>>,
+}
+
+/// State shared by every command of a single test run.
+struct TestContext<'a> {
+    extension: Extension,
+    inputs: &'a InteractiveInputs,
+    host: Arc<Host>,
+    /// `maxMemoryMb` of the trace
+    max_memory_mb: Option<f64>,
+    /// `maxFuel` of the trace
+    max_fuel: Option<u64>,
+    print: bool,
+}
+
+/// Markers of a wasm trap in an error returned by the extension, e.g. after a panic. A Rust
+/// panic ends in the trap "wasm `unreachable` instruction executed", while "unreachable" on
+/// its own is common in ordinary errors such as "server unreachable".
+const PANIC_MARKERS: &[&str] = &[
+    "panicked",
+    "wasm trap",
+    "`unreachable` instruction executed",
+    "unreachable executed",
+];
+
+/// Start of the error of a command that did not respond in time.
+const TIMEOUT_ERROR: &str = "No response within";
+
+/// Start of the error of a command over its `maxMemoryMb` or `maxFuel`.
+const LIMIT_ERROR: &str = "Resource limit exceeded:";
+
+/// Whether an error returned by the extension comes from a wasm trap rather than from the
+/// extension returning an error.
+fn is_panic(message: &str) -> bool {
+    let lower = message.to_lowercase();
+    PANIC_MARKERS.iter().any(|m| lower.contains(m))
+}
+
+/// Points out errors caused by the extension panicking rather than returning an error.
+fn describe_send_error(e: MoosyncError) -> MoosyncError {
+    let message = e.to_string();
+    if is_panic(&message) {
+        MoosyncError::String(format!("Extension panicked: {}", message))
+    } else {
+        e
+    }
+}
+
+/// Checks the outcome of a sent command against its `expected` response or
+/// `expectedError`, and records the response in its report.
+fn check_outcome(
+    sent: Result<Value>,
+    expected: Option<Value>,
+    expected_error: Option<ErrorMatcher>,
+    phase: Phase,
+    print: bool,
+    report: &mut CommandReport,
+) -> Result<()> {
+    match (sent, expected_error) {
+        (Ok(resp), None) => {
+            let checked = check_response(&resp, expected, phase, print);
+            report.response = Some(resp);
+            checked
+        }
+        (Ok(resp), Some(matcher)) => {
+            let error = format!(
+                "Expected an error matching {}, received response: {}",
+                matcher,
+                serde_json::to_string_pretty(&resp).unwrap()
+            );
+            report.response = Some(resp);
+            Err(error.into())
+        }
+        (Err(e), Some(matcher)) => matcher.check(&e).inspect(|_| {
+            report!(print, "Received expected error {:?}", e.to_string());
+        }),
+        (Err(e), None) => Err(describe_send_error(e)),
+    }
+}
+
+fn fail_command(report: &mut CommandReport, total_commands: usize, e: MoosyncError) -> String {
+    let error = format!(
+        "{} [{}/{}] {} failed:\n{}",
+        report.phase.label(),
+        report.index + 1,
+        total_commands,
+        report.name,
+        e
+    );
+    report.status = CommandStatus::Failed;
+    report.error = Some(error.clone());
+    error
+}
+
+async fn run_commands(
+    ctx: &TestContext<'_>,
+    phase: Phase,
+    commands: Vec<CommandWrapper>,
+    selected: Option<&[bool]>,
+    reports: &mut Vec<CommandReport>,
+) -> Result<()> {
+    let total_commands = commands.len();
+    let mut commands = commands.into_iter().enumerate().peekable();
+
+    while let Some((i, command)) = commands.next() {
+        let parallel = command.parallel;
+        let mut batch = vec![(i, command)];
+        if let Some(group) = parallel {
+            while let Some(next) =
+                commands.next_if(|(_, c)| c.parallel.is_some_and(|p| p.id == group.id))
+            {
+                batch.push(next);
+            }
+        }
+
+        run_batch(
+            ctx,
+            phase,
+            batch,
+            parallel,
+            total_commands,
+            selected,
+            reports,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Sends a single command, or every command of a parallel group concurrently, and checks
+/// their responses. Failures of a group are reported once all of its commands finished.
+async fn run_batch(
+    ctx: &TestContext<'_>,
+    phase: Phase,
+    batch: Vec<(usize, CommandWrapper)>,
+    parallel: Option<Parallel>,
+    total_commands: usize,
+    selected: Option<&[bool]>,
+    reports: &mut Vec<CommandReport>,
+) -> Result<()> {
+    let print = ctx.print;
+    let mut done = Vec::new();
+    let mut pending = Vec::new();
+    let mut errors = Vec::new();
+
+    for (i, mut command) in batch {
+        let command_desc = describe_command(&command);
+        let mut report = CommandReport {
+            phase,
+            index: i,
+            name: command_desc.clone(),
+            kind: command_type(&command.command),
+            status: CommandStatus::Skipped,
+            response: None,
+            error: None,
+            duration_ms: 0.0,
+            memory: None,
+            fuel: None,
+            parallel: parallel.map(|group| group.id),
+            host_calls: Vec::new(),
+        };
+
+        if !selected.is_none_or(|s| s[i]) {
+            report!(
+                print,
+                "\n{} [{}/{}]: {} {}",
+                phase.label(),
+                i + 1,
+                total_commands,
+                command_desc.dimmed(),
+                "(skipped)".yellow()
+            );
+            done.push(report);
+            continue;
+        }
+
+        report!(
+            print,
+            "\n{} [{}/{}]: {}{}",
+            phase.label(),
+            i + 1,
+            total_commands,
+            command_desc.magenta(),
+            if parallel.is_some() {
+                " (parallel)"
+            } else {
+                ""
+            }
+            .dimmed()
+        );
+
+        match ctx.inputs.resolve(&mut command) {
+            Ok(_) => pending.push((command, report)),
+            Err(e) => {
+                errors.push(fail_command(&mut report, total_commands, e));
+                done.push(report);
+            }
+        }
+    }
+
+    let sends = pending.iter().map(|(command, _)| async move {
+        let started = Instant::now();
+        let send = ctx.extension.send(command.command.clone());
+        let sent = match parallel {
+            Some(group) => tokio::time::timeout(group.timeout, send)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(format!(
+                        "{} {}ms, the extension may be deadlocked",
+                        TIMEOUT_ERROR,
+                        group.timeout.as_millis()
+                    )
+                    .into())
+                }),
+            None => send.await,
+        };
+        (sent, started.elapsed().as_secs_f64() * 1000.0)
+    });
+    // Commands of a group share the strictest limits, as their usage cannot be told apart
+    let max_memory_mb = pending
+        .iter()
+        .filter_map(|(command, _)| command.max_memory_mb.or(ctx.max_memory_mb))
+        .reduce(f64::min);
+    let max_fuel = pending
+        .iter()
+        .filter_map(|(command, _)| command.max_fuel.or(ctx.max_fuel))
+        .min();
+    // Left over from loading the extension or from skipped commands
+    ctx.extension.take_usage();
+    let mut results = join_all(sends).await;
+    if !pending.is_empty() && (max_memory_mb.is_some() || max_fuel.is_some()) {
+        // Limits cannot be checked without the reports of the module
+        drop(ctx.extension.reports()?);
+    }
+    let usage = ctx.extension.take_usage().filter(|_| !pending.is_empty());
+    let memory = usage.as_ref().map(MemoryUsage::new);
+    if let (Some(usage), Some(memory)) = (&usage, &memory) {
+        if let Err(e) = check_limits(usage, max_memory_mb, max_fuel) {
+            let error = e.to_string();
+            for (sent, _) in &mut results {
+                *sent = Err(error.as_str().into());
+            }
+        }
+        report!(
+            print,
+            "{}",
+            format!(
+                "Memory: {:.1}MB ({:+.1}MB), fuel: {}",
+                memory.peak_mb, memory.growth_mb, usage.fuel
+            )
+            .dimmed()
+        );
+    }
+
+    // Requests are answered outside the task that sent the command, so the requests made
+    // while a group runs cannot be told apart and are all reported on its first command
+    let mut host_calls = Some(ctx.host.take_calls());
+    for ((command, mut report), (sent, duration_ms)) in pending.into_iter().zip(results) {
+        report.duration_ms = duration_ms;
+        report.memory = memory;
+        report.fuel = usage.map(|usage| usage.fuel);
+        report.host_calls = host_calls.take().unwrap_or_default();
+
+        match check_outcome(
+            sent,
+            command.expected,
+            command.expected_error,
+            phase,
+            print,
+            &mut report,
+        ) {
+            Ok(_) => {
+                report.status = CommandStatus::Passed;
+                report!(print, "✓ Successful: {}", report.name.green());
+            }
+            Err(e) => errors.push(fail_command(&mut report, total_commands, e)),
+        }
+        done.push(report);
+    }
+
+    done.sort_by_key(|r| r.index);
+    reports.extend(done);
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n\n").into())
+    }
+}
+
+async fn run_test(run: TestRun, selected: Vec<bool>, opts: &RunOptions) -> Result<TraceReport> {
+    let print = opts.print;
+    let TestRun {
+        name,
+        test_case,
+        vars,
+        dir,
+    } = run;
+    report!(
+        print,
+        "{} {} commands and {} requests\n",
+        "Loaded test case with".blue(),
+        test_case.commands.len(),
+        test_case.requests.len()
+    );
+
+    let meter = test_case.meter();
+    let mut mocks = MockSet::new(&vars, &dir)?;
+    for mock in test_case
+        .requests
+        .into_iter()
+        .chain(opts.mocks.iter().cloned())
+    {
+        mocks.push(mock)?;
+    }
+    let assertions = test_case
+        .host_call_assertions
+        .into_iter()
+        .map(HostCallAssertion::parse)
+        .collect::<Result<Vec<_>>>()?;
+
+    let host = Arc::new(Host::new(
+        mocks,
+        opts.responder.clone(),
+        opts.host_latency,
+        print,
+    ));
+    let extension = start_extension(
+        &opts.manifest_path,
+        reply_handler(host.clone()),
+        opts.verbose,
+        print,
+        Instrumentation {
+            coverage: opts.wasm_coverage.is_some(),
+            meter,
+            calls: false,
+        },
+    )
+    .await?;
+    let startup_host_calls = host.take_calls();
+    let ctx = TestContext {
+        extension,
+        inputs: &opts.inputs,
+        host,
+        max_memory_mb: test_case.max_memory_mb,
+        max_fuel: test_case.max_fuel,
+        print,
+    };
+
+    report!(
+        print,
+        "\n------------------------------------------------------------"
+    );
+    report!(
+        print,
+        "{} {} {}",
+        "=== Running commands from test case".cyan(),
+        name.cyan(),
+        "... ===".cyan()
+    );
+
+    let total_commands =
+        test_case.setup.len() + test_case.commands.len() + test_case.teardown.len();
+    let mut commands = Vec::new();
+    let mut result = run_commands(&ctx, Phase::Setup, test_case.setup, None, &mut commands).await;
+
+    if result.is_ok() {
+        result = run_commands(
+            &ctx,
+            Phase::Test,
+            test_case.commands,
+            Some(&selected),
+            &mut commands,
+        )
+        .await;
+    }
+
+    // Teardown always runs, even if setup or a command failed
+    let teardown = run_commands(
+        &ctx,
+        Phase::Teardown,
+        test_case.teardown,
+        None,
+        &mut commands,
+    )
+    .await;
+
+    // Skipped or unsent commands make the host calls of the run incomplete
+    let complete = commands.len() == total_commands
+        && commands.iter().all(|c| c.status != CommandStatus::Skipped);
+    let (unchecked_assertions, checked) =
+        check_host_calls(&assertions, &startup_host_calls, &commands, complete);
+    if !unchecked_assertions.is_empty() {
+        report!(
+            print,
+            "\n{}\n  {}",
+            "Host call assertions not checked:".yellow(),
+            unchecked_assertions.join("\n  ").yellow()
+        );
+    }
+
+    if let Some(wasm_coverage) = &opts.wasm_coverage {
+        let reports = ctx.extension.reports()?;
+        wasm_coverage
+            .lock()
+            .unwrap()
+            .add(&ctx.extension.code, &reports.counts);
+    }
+
+    let error = [result, teardown, checked]
+        .into_iter()
+        .filter_map(|r| r.err().map(|e| e.to_string()))
+        .collect::<Vec<_>>();
+    let error = (!error.is_empty()).then(|| error.join("\n\n"));
+
+    let coverage = MockCoverage::new(
+        ctx.host.mocks.read().unwrap().labels(),
+        startup_host_calls
+            .iter()
+            .chain(commands.iter().flat_map(|c| &c.host_calls)),
+    );
+    if !coverage.mocks.is_empty() || !coverage.fallbacks.is_empty() {
+        report!(print, "\n{}\n{}", "Mock coverage:".cyan(), coverage);
+    }
+
+    if error.is_none() {
+        report!(
+            print,
+            "{} {} {}",
+            "=== Completed test case".cyan(),
+            name.cyan(),
+            "... ===".cyan()
+        );
+    }
+
+    Ok(TraceReport {
+        name,
+        passed: error.is_none(),
+        skipped: false,
+        startup_host_calls,
+        commands,
+        coverage,
+        unchecked_assertions,
+        error,
+    })
+}
+
+/// Runs every case of a trace file and returns one report per case.
+/// Errors are only returned when the trace could not be run at all.
+async fn run_trace(file: &Path, opts: &RunOptions) -> Result<Vec<TraceReport>> {
+    let mut reports = Vec::new();
+    for run in parse_test_case(file)? {
+        let selected = opts.selection.select(&run);
+        if !selected.iter().any(|s| *s) {
+            report!(
+                opts.print,
+                "{} {}",
+                "- Skipped:".yellow(),
+                run.name.yellow()
+            );
+            reports.push(TraceReport::skipped(run.name));
+            continue;
+        }
+
+        let report = run_test(run, selected, opts).await?;
+        let passed = report.passed;
+        if passed {
+            report!(
+                opts.print,
+                "{} {}",
+                "✓ Passed:".green(),
+                report.name.green()
+            );
+        }
+        reports.push(report);
+
+        if !passed && opts.fail_fast {
+            break;
+        }
+    }
+
+    Ok(reports)
+}
+
+/// Lists every trace file under `dir`, skipping fixture directories.
+fn collect_trace_files(dir: &Path) -> Vec<PathBuf> {
+    WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| !(e.file_type().is_dir() && e.file_name() == FIXTURES_DIR))
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && is_trace_file(e.path()))
+        .map(|e| e.into_path())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Steps {
+        #[serde(deserialize_with = "deserialize_commands")]
+        commands: Vec<CommandWrapper>,
+    }
+
+    fn steps(commands: Value) -> serde_json::Result<Vec<CommandWrapper>> {
+        serde_json::from_value::<Steps>(json!({ "commands": commands })).map(|s| s.commands)
+    }
+
+    #[test]
+    fn parallel_groups_are_flattened_into_their_commands() {
+        let commands = steps(json!([
+            { "type": "seeked", "data": [1] },
+            {
+                "parallel": [
+                    { "type": "seeked", "data": [2] },
+                    { "type": "seeked", "data": [3] }
+                ],
+                "timeoutMs": 500
+            },
+            { "parallel": [{ "type": "seeked", "data": [4] }] }
+        ]))
+        .unwrap();
+
+        let groups: Vec<_> = commands
+            .iter()
+            .map(|c| c.parallel.map(|p| (p.id, p.timeout.as_millis())))
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                None,
+                Some((1, 500)),
+                Some((1, 500)),
+                Some((2, DEFAULT_PARALLEL_TIMEOUT_MS as u128))
+            ]
+        );
+    }
+
+    #[test]
+    fn parallel_groups_cannot_be_nested() {
+        assert!(
+            steps(json!([{
+                "parallel": [{ "parallel": [{ "type": "seeked", "data": [1] }] }]
+            }]))
+            .is_err()
+        );
+        assert!(
+            steps(json!([{
+                "parallel": [{ "type": "seeked", "data": [1] }],
+                "timeout": 5
+            }]))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn only_traps_are_panics() {
+        assert!(is_panic(
+            "error while executing at wasm backtrace: wasm `unreachable` instruction executed"
+        ));
+        assert!(is_panic("Extension panicked at src/lib.rs:10:5"));
+        assert!(!is_panic("Server unreachable"));
+        assert!(!is_panic("Song not found"));
+    }
+}
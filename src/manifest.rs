@@ -1,7 +1,20 @@
-use std::{fs::File, path::Path};
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io,
+    path::{Component, Path, PathBuf},
+};
 
 use types::{errors::Result, extensions::ExtensionManifest};
 
+fn entry_path(manifest_path: &Path, manifest: ExtensionManifest) -> PathBuf {
+    if let Some(parent) = manifest_path.parent() {
+        parent.join(manifest.extension_entry)
+    } else {
+        manifest.extension_entry
+    }
+}
+
 pub(crate) fn validate_manifest(manifest_path: &Path) -> Result<()> {
     if !manifest_path.exists() {
         return Err(format!("Manifest does not exist at path: {:?}", manifest_path).into());
@@ -15,11 +28,7 @@ pub(crate) fn validate_manifest(manifest_path: &Path) -> Result<()> {
                 return Err("Manifest is not of a moosync extension".into());
             }
 
-            let ext_entry = if let Some(parent) = manifest_path.parent() {
-                parent.join(manifest.extension_entry)
-            } else {
-                manifest.extension_entry
-            };
+            let ext_entry = entry_path(manifest_path, manifest);
             if !ext_entry.exists() {
                 return Err(format!("Extension path: {:?} does not exist", ext_entry).into());
             }
@@ -29,3 +38,90 @@ pub(crate) fn validate_manifest(manifest_path: &Path) -> Result<()> {
 
     Ok(())
 }
+
+/// Returns the path of the wasm file referenced by the manifest.
+pub(crate) fn extension_entry(manifest_path: &Path) -> Result<PathBuf> {
+    let file = File::open(manifest_path)
+        .map_err(|e| format!("Failed to open manifest {:?}: {}", manifest_path, e))?;
+    let manifest = serde_json::from_reader::<_, ExtensionManifest>(file)
+        .map_err(|e| format!("Failed to read manifest: {}", e))?;
+    Ok(entry_path(manifest_path, manifest))
+}
+
+/// Mirrors the directory of the manifest into `dir`, with `wasm` as the extension entry at
+/// the same path relative to the manifest. The manifest is copied and the directories on
+/// the way to the entry are created, while everything else in them links to the original,
+/// so the extension still finds the files it ships with.
+pub(crate) fn mirror_with_entry(manifest_path: &Path, dir: &Path, wasm: &[u8]) -> Result<()> {
+    let root = manifest_path.parent().unwrap_or(Path::new(""));
+    let entry = extension_entry(manifest_path)?;
+    let relative = entry
+        .strip_prefix(root)
+        .ok()
+        .filter(|r| r.components().all(|c| matches!(c, Component::Normal(_))))
+        .ok_or_else(|| {
+            format!(
+                "Extension entry {:?} must be inside the directory of the manifest to be instrumented",
+                entry
+            )
+        })?;
+    let manifest_name = manifest_path.file_name().unwrap_or_default();
+
+    let (mut from, mut to) = (root.to_path_buf(), dir.to_path_buf());
+    fs::create_dir_all(&to).map_err(|e| format!("Failed to create {}: {}", to.display(), e))?;
+    for (i, component) in relative.iter().enumerate() {
+        let skipped = if i == 0 {
+            vec![component, manifest_name]
+        } else {
+            vec![component]
+        };
+        link_entries(&from, &to, &skipped)?;
+        from.push(component);
+        to.push(component);
+        if from != entry {
+            fs::create_dir(&to).map_err(|e| format!("Failed to create {}: {}", to.display(), e))?;
+        }
+    }
+
+    fs::write(&to, wasm).map_err(|e| format!("Failed to write {}: {}", to.display(), e))?;
+    fs::copy(manifest_path, dir.join(manifest_name))
+        .map_err(|e| format!("Failed to copy {:?}: {}", manifest_path, e))?;
+    Ok(())
+}
+
+/// Links every entry of the directory `from` into `to`, except the `skipped` ones.
+fn link_entries(from: &Path, to: &Path, skipped: &[&OsStr]) -> Result<()> {
+    // The directory of a manifest given by its file name alone
+    let from = if from.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        from
+    };
+    let read = |e: io::Error| format!("Failed to read {}: {}", from.display(), e);
+    for item in fs::read_dir(from).map_err(read)? {
+        let name = item.map_err(read)?.file_name();
+        if skipped.contains(&name.as_os_str()) {
+            continue;
+        }
+        let original = std::path::absolute(from.join(&name))
+            .map_err(|e| format!("Failed to read {}: {}", from.join(&name).display(), e))?;
+        let link = to.join(&name);
+        symlink(&original, &link)
+            .map_err(|e| format!("Failed to link {}: {}", link.display(), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    if original.is_dir() {
+        std::os::windows::fs::symlink_dir(original, link)
+    } else {
+        std::os::windows::fs::symlink_file(original, link)
+    }
+}
@@ -0,0 +1,261 @@
+use glob::Pattern;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use types::errors::Result;
+
+use crate::utils::is_subset;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPredicate {
+    pointer: Option<String>,
+    subset: Option<Value>,
+    equals: Option<Value>,
+    regex: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawMatcher {
+    All(Vec<RawPredicate>),
+    One(RawPredicate),
+}
+
+/// A condition on the payload of a host request.
+///
+/// `pointer` selects the part of the payload the other checks apply to, and on its own
+/// only requires it to exist. `regex` is matched against strings as is and against other
+/// values as JSON.
+struct Predicate {
+    pointer: Option<String>,
+    subset: Option<Value>,
+    equals: Option<Value>,
+    regex: Option<Regex>,
+}
+
+impl Predicate {
+    fn matches(&self, payload: &Value) -> bool {
+        let target = match &self.pointer {
+            Some(pointer) => match payload.pointer(pointer) {
+                Some(target) => target,
+                None => return false,
+            },
+            None => payload,
+        };
+
+        if self.subset.as_ref().is_some_and(|s| !is_subset(s, target)) {
+            return false;
+        }
+        if self.equals.as_ref().is_some_and(|e| e != target) {
+            return false;
+        }
+        self.regex.as_ref().is_none_or(|regex| match target {
+            Value::String(s) => regex.is_match(s),
+            other => regex.is_match(&other.to_string()),
+        })
+    }
+}
+
+/// The `match` of a request mock. The mock only answers requests whose payload satisfies
+/// every predicate.
+pub(crate) struct RequestMatcher {
+    predicates: Vec<Predicate>,
+}
+
+impl RequestMatcher {
+    pub(crate) fn from_value(value: Value) -> Result<Self> {
+        let raw = match serde_json::from_value(value)
+            .map_err(|e| format!("Invalid match of request mock: {}", e))?
+        {
+            RawMatcher::All(predicates) => predicates,
+            RawMatcher::One(predicate) => vec![predicate],
+        };
+
+        let predicates = raw
+            .into_iter()
+            .map(|p| {
+                let regex = p
+                    .regex
+                    .map(|r| {
+                        Regex::new(&r).map_err(|e| format!("Invalid match regex {}: {}", r, e))
+                    })
+                    .transpose()?;
+                Ok(Predicate {
+                    pointer: p.pointer,
+                    subset: p.subset,
+                    equals: p.equals,
+                    regex,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { predicates })
+    }
+
+    pub(crate) fn matches(&self, payload: &Value) -> bool {
+        self.predicates.iter().all(|p| p.matches(payload))
+    }
+}
+
+enum KeyPattern {
+    Exact(String),
+    Glob(Pattern),
+    Regex(Regex),
+}
+
+/// Compares the key of a `getPreference` or `getSecure` mock with requested keys.
+///
+/// Requested keys are prefixed with `extensions.<package>.`, which is stripped before
+/// comparing unless the mock sets `fullKey`.
+pub(crate) struct KeyMatcher {
+    pattern: KeyPattern,
+    full_key: bool,
+}
+
+impl KeyMatcher {
+    /// Keys wrapped in slashes are regexes, and keys containing `*`, `?` or `[` are globs.
+    pub(crate) fn new(key: &str, full_key: bool) -> Result<Self> {
+        let regex = key
+            .strip_prefix('/')
+            .and_then(|k| k.strip_suffix('/'))
+            .filter(|r| !r.is_empty());
+
+        let pattern = if let Some(regex) = regex {
+            KeyPattern::Regex(
+                Regex::new(regex).map_err(|e| format!("Invalid key regex {}: {}", regex, e))?,
+            )
+        } else if key.contains(['*', '?', '[']) {
+            KeyPattern::Glob(
+                Pattern::new(key).map_err(|e| format!("Invalid key pattern {}: {}", key, e))?,
+            )
+        } else {
+            KeyPattern::Exact(key.to_string())
+        };
+
+        Ok(Self { pattern, full_key })
+    }
+
+    /// The part of a requested key that is compared, which responses carry. `None` if the
+    /// key lacks the prefix that is stripped.
+    pub(crate) fn compared<'a>(&self, package_name: &str, requested: &'a str) -> Option<&'a str> {
+        if self.full_key {
+            return Some(requested);
+        }
+        requested
+            .strip_prefix("extensions.")?
+            .strip_prefix(package_name)?
+            .strip_prefix('.')
+    }
+
+    /// Returns the compared part of the requested key if it matches.
+    pub(crate) fn matches<'a>(&self, package_name: &str, requested: &'a str) -> Option<&'a str> {
+        let key = self.compared(package_name, requested)?;
+        let matched = match &self.pattern {
+            KeyPattern::Exact(exact) => exact == key,
+            KeyPattern::Glob(pattern) => pattern.matches(key),
+            KeyPattern::Regex(regex) => regex.is_match(key),
+        };
+        matched.then_some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn matcher(value: Value) -> RequestMatcher {
+        RequestMatcher::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn pointer_alone_requires_the_field() {
+        let matcher = matcher(json!({ "pointer": "/song/title" }));
+        assert!(matcher.matches(&json!({ "song": { "title": null } })));
+        assert!(!matcher.matches(&json!({ "song": {} })));
+    }
+
+    #[test]
+    fn subset_equals_and_regex_apply_to_the_pointed_value() {
+        let payload =
+            json!({ "song": { "title": "Bohemian", "duration": 354, "tags": ["rock", "70s"] } });
+        assert!(
+            matcher(json!({ "pointer": "/song", "subset": { "title": "Bohemian" } }))
+                .matches(&payload)
+        );
+        assert!(matcher(json!({ "subset": { "song": { "tags": ["rock"] } } })).matches(&payload));
+        assert!(!matcher(json!({ "subset": { "song": { "tags": ["70s"] } } })).matches(&payload));
+        assert!(matcher(json!({ "pointer": "/song/duration", "equals": 354 })).matches(&payload));
+        assert!(
+            !matcher(json!({ "pointer": "/song/duration", "equals": "354" })).matches(&payload)
+        );
+        assert!(matcher(json!({ "pointer": "/song/title", "regex": "^Bo" })).matches(&payload));
+        assert!(
+            matcher(json!({ "pointer": "/song/duration", "regex": "^3\\d+$" })).matches(&payload)
+        );
+    }
+
+    #[test]
+    fn every_predicate_of_a_list_must_match() {
+        let matcher = matcher(json!([
+            { "pointer": "/a", "equals": 1 },
+            { "pointer": "/b", "regex": "x" }
+        ]));
+        assert!(matcher.matches(&json!({ "a": 1, "b": "xyz" })));
+        assert!(!matcher.matches(&json!({ "a": 1, "b": "yz" })));
+        assert!(!matcher.matches(&json!({ "a": 2, "b": "xyz" })));
+    }
+
+    #[test]
+    fn keys_are_compared_without_the_extension_prefix() {
+        let exact = KeyMatcher::new("session", false).unwrap();
+        assert_eq!(
+            exact.matches("pkg", "extensions.pkg.session"),
+            Some("session")
+        );
+        assert_eq!(exact.matches("pkg", "extensions.other.session"), None);
+        assert_eq!(exact.matches("pkg", "session"), None);
+
+        let full = KeyMatcher::new("extensions.other.*", true).unwrap();
+        assert_eq!(
+            full.matches("pkg", "extensions.other.token"),
+            Some("extensions.other.token")
+        );
+        assert_eq!(full.compared("pkg", "anything"), Some("anything"));
+    }
+
+    #[test]
+    fn keys_can_be_globs_or_regexes() {
+        let glob = KeyMatcher::new("accounts.*.token", false).unwrap();
+        assert_eq!(
+            glob.matches("pkg", "extensions.pkg.accounts.1.token"),
+            Some("accounts.1.token")
+        );
+        assert_eq!(
+            glob.matches("pkg", "extensions.pkg.accounts.1.secret"),
+            None
+        );
+
+        let regex = KeyMatcher::new("/^session_\\d+$/", false).unwrap();
+        assert!(regex.matches("pkg", "extensions.pkg.session_12").is_some());
+        assert!(regex.matches("pkg", "extensions.pkg.session_x").is_none());
+
+        assert!(KeyMatcher::new("/(/", false).is_err());
+        // An empty regex is taken as the key itself
+        assert!(
+            KeyMatcher::new("//", false)
+                .unwrap()
+                .matches("pkg", "//")
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn invalid_matchers_are_rejected() {
+        assert!(RequestMatcher::from_value(json!({ "regex": "(" })).is_err());
+        assert!(RequestMatcher::from_value(json!({ "pointr": "/a" })).is_err());
+        assert!(RequestMatcher::from_value(json!("a")).is_err());
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use rhai::{
+    AST, Dynamic, Engine, Scope,
+    serde::{from_dynamic, to_dynamic},
+};
+use serde_json::{Map, Value, json};
+use types::{
+    errors::{MoosyncError, Result},
+    extensions::{MainCommand, MainCommandResponse},
+};
+
+use crate::{
+    MainCommandParsable, create_response_from_request,
+    host::{Delay, Latency},
+    matcher::{KeyMatcher, RequestMatcher},
+};
+
+enum MockResponse {
+    Static(MainCommandParsable),
+    /// A Rhai script that returns the `data` of the response, or `()` to let the
+    /// following mocks answer the request
+    Script(AST),
+    /// Fails the request with this error
+    Error(String),
+}
+
+/// The answer of a mock to a host request.
+pub(crate) struct MockReply {
+    /// Position of the mock that answered
+    pub(crate) index: usize,
+    pub(crate) response: Result<MainCommandResponse>,
+    /// Simulated delay before the response, from `delayMs`
+    pub(crate) delay: Option<Duration>,
+}
+
+/// A host request mock from the `requests` of a trace.
+struct RequestMock {
+    kind: String,
+    /// Short description for coverage reports, e.g. `getSecure session`
+    label: String,
+    response: MockResponse,
+    /// Calls of this request type the mock is used for, counting from 1. All calls if unset.
+    on_call: Option<Vec<usize>>,
+    /// Condition on the payload of the requests the mock answers
+    matcher: Option<RequestMatcher>,
+    /// Key of a `getPreference` or `getSecure` mock
+    key: Option<KeyMatcher>,
+    /// Whether the mock only answers requests that no other mock answers
+    default: bool,
+    delay: Option<Delay>,
+}
+
+impl RequestMock {
+    fn parse(value: Value, engine: &Engine, base_dir: &Path) -> Result<Self> {
+        let Value::Object(mut map) = value else {
+            return Err("Request mocks must be objects".into());
+        };
+
+        let kind = map
+            .get("type")
+            .and_then(|t| t.as_str())
+            .ok_or_else(|| MoosyncError::String("Request mock is missing its type".into()))?
+            .to_string();
+
+        let on_call = match map.remove("onCall") {
+            Some(Value::Array(calls)) => Some(serde_json::from_value(Value::Array(calls))?),
+            Some(call) => Some(vec![serde_json::from_value(call)?]),
+            None => None,
+        };
+
+        let matcher = map
+            .remove("match")
+            .map(RequestMatcher::from_value)
+            .transpose()?;
+
+        let mut flag = |name: &str| match map.remove(name) {
+            Some(Value::Bool(flag)) => Ok(flag),
+            Some(_) => Err(MoosyncError::String(format!(
+                "{} of {} mock must be a boolean",
+                name, kind
+            ))),
+            None => Ok(false),
+        };
+        let default = flag("default")?;
+        let full_key = flag("fullKey")?;
+
+        let delay = map
+            .remove("delayMs")
+            .map(|delay| Latency::from_value(&delay))
+            .transpose()?
+            .map(Delay::new);
+
+        let error = match map.remove("error") {
+            Some(Value::String(error)) => Some(error),
+            Some(_) => return Err(format!("Error of {} mock must be a string", kind).into()),
+            None => None,
+        };
+
+        let script = match (map.remove("script"), map.remove("scriptFile")) {
+            (Some(Value::String(source)), None) => Some(source),
+            (None, Some(Value::String(file))) => Some(
+                fs::read_to_string(base_dir.join(&file))
+                    .map_err(|e| format!("Failed to read script {}: {}", file, e))?,
+            ),
+            (None, None) => None,
+            _ => {
+                return Err(format!(
+                    "Mock for {} must have either a script or a scriptFile string",
+                    kind
+                )
+                .into());
+            }
+        };
+
+        let response = match (error, script) {
+            (Some(_), Some(_)) => {
+                return Err(
+                    format!("Mock for {} cannot have both an error and a script", kind).into(),
+                );
+            }
+            (Some(error), None) => MockResponse::Error(error),
+            (None, Some(source)) => MockResponse::Script(
+                engine
+                    .compile(&source)
+                    .map_err(|e| format!("Failed to compile script of {} mock: {}", kind, e))?,
+            ),
+            (None, None) => MockResponse::Static(serde_json::from_value(Value::Object(map))?),
+        };
+
+        let (key, mut label) = match &response {
+            MockResponse::Static(
+                MainCommandParsable::GetPreference(data) | MainCommandParsable::GetSecure(data),
+            ) => (
+                Some(KeyMatcher::new(&data.key, full_key)?),
+                format!("{} {}", kind, data.key),
+            ),
+            MockResponse::Static(_) => (None, kind.clone()),
+            MockResponse::Script(_) => (None, format!("{} (script)", kind)),
+            MockResponse::Error(_) => (None, format!("{} (error)", kind)),
+        };
+        if default {
+            label.push_str(" (default)");
+        }
+
+        Ok(Self {
+            kind,
+            label,
+            response,
+            on_call,
+            matcher,
+            key,
+            default,
+            delay,
+        })
+    }
+}
+
+/// A preference mock answering with the key it matched instead of its pattern.
+fn with_key(request: &MainCommandParsable, key: &str) -> MainCommandParsable {
+    let mut request = request.clone();
+    if let MainCommandParsable::GetPreference(data) | MainCommandParsable::GetSecure(data) =
+        &mut request
+    {
+        data.key = key.to_string();
+    }
+    request
+}
+
+/// Operations a single run of a mock script may take, so a script stuck in a loop fails the
+/// request instead of hanging the run.
+const MAX_SCRIPT_OPERATIONS: u64 = 1_000_000;
+
+/// Depth of nested function calls in mock scripts.
+const MAX_SCRIPT_CALL_LEVELS: usize = 64;
+
+fn script_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_SCRIPT_OPERATIONS);
+    engine.set_max_call_levels(MAX_SCRIPT_CALL_LEVELS);
+    engine
+}
+
+fn script_error(e: impl Display) -> MoosyncError {
+    MoosyncError::String(e.to_string())
+}
+
+/// The `type` of a host request, as written in the `requests` of a trace.
+pub(crate) fn command_kind(command: &MainCommand) -> Result<String> {
+    serde_json::to_value(command)?
+        .get("type")
+        .and_then(|t| t.as_str())
+        .map(str::to_string)
+        .ok_or_else(|| "Host request has no type".into())
+}
+
+/// The request mocks of a single test run.
+///
+/// Mocks are tried in order. Scripts see the request as `command`, the trace variables as
+/// `vars` and a `state` object that is kept between calls for the whole run.
+pub(crate) struct MockSet {
+    mocks: Vec<RequestMock>,
+    engine: Engine,
+    vars: Dynamic,
+    state: Mutex<Dynamic>,
+    /// Number of requests received so far, by type
+    calls: Mutex<HashMap<String, usize>>,
+    /// Directory `scriptFile` paths are relative to
+    base_dir: PathBuf,
+}
+
+impl MockSet {
+    pub(crate) fn new(vars: &Map<String, Value>, base_dir: &Path) -> Result<Self> {
+        Ok(Self {
+            mocks: Vec::new(),
+            engine: script_engine(),
+            vars: to_dynamic(vars).map_err(script_error)?,
+            state: Mutex::new(Dynamic::from_map(Default::default())),
+            calls: Mutex::new(HashMap::new()),
+            base_dir: base_dir.to_path_buf(),
+        })
+    }
+
+    pub(crate) fn push(&mut self, mock: Value) -> Result<()> {
+        let mock = RequestMock::parse(mock, &self.engine, &self.base_dir)?;
+        self.mocks.push(mock);
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, index: usize) {
+        self.mocks.remove(index);
+    }
+
+    /// Type and description of every mock, in the order they were added.
+    pub(crate) fn labels(&self) -> Vec<(String, String)> {
+        self.mocks
+            .iter()
+            .map(|m| (m.kind.clone(), m.label.clone()))
+            .collect()
+    }
+
+    /// Returns the reply of the first mock that answers `command`, of type `kind` and with
+    /// the given `data` payload, or `None` if no mock does. Error mocks fail the request with their error.
+    pub(crate) fn respond(
+        &self,
+        package_name: &str,
+        command: &MainCommand,
+        kind: &str,
+        payload: &Value,
+    ) -> Option<MockReply> {
+        let call = {
+            let mut calls = self.calls.lock().unwrap();
+            let count = calls.entry(kind.to_string()).or_default();
+            *count += 1;
+            *count
+        };
+
+        let requested_key = match command {
+            MainCommand::GetPreference(pref) | MainCommand::GetSecure(pref) => Some(&pref.key),
+            _ => None,
+        };
+
+        let (defaults, mocks): (Vec<_>, Vec<_>) =
+            self.mocks.iter().enumerate().partition(|(_, m)| m.default);
+        for (index, mock) in mocks.into_iter().chain(defaults) {
+            if mock.kind != kind {
+                continue;
+            }
+            if !mock
+                .on_call
+                .as_ref()
+                .is_none_or(|calls| calls.contains(&call))
+            {
+                continue;
+            }
+            if mock.matcher.as_ref().is_some_and(|m| !m.matches(payload)) {
+                continue;
+            }
+
+            // Default preference mocks answer any key
+            let key = match (&mock.key, requested_key) {
+                (Some(matcher), Some(requested)) if mock.default => Some(
+                    matcher
+                        .compared(package_name, requested)
+                        .unwrap_or(requested),
+                ),
+                (Some(matcher), Some(requested)) => {
+                    match matcher.matches(package_name, requested) {
+                        Some(key) => Some(key),
+                        None => continue,
+                    }
+                }
+                _ => None,
+            };
+
+            let response = match &mock.response {
+                MockResponse::Static(request) => Ok(match key {
+                    Some(key) => create_response_from_request(&with_key(request, key)),
+                    None => create_response_from_request(request),
+                }),
+                MockResponse::Script(ast) => match self.run_script(kind, ast, command) {
+                    Ok(Some(response)) => Ok(response),
+                    Ok(None) => continue,
+                    Err(e) => Err(e),
+                },
+                MockResponse::Error(error) => Err(MoosyncError::String(error.clone())),
+            };
+
+            return Some(MockReply {
+                index,
+                response,
+                delay: mock.delay.as_ref().map(Delay::sample),
+            });
+        }
+
+        None
+    }
+
+    fn run_script(
+        &self,
+        kind: &str,
+        ast: &AST,
+        command: &MainCommand,
+    ) -> Result<Option<MainCommandResponse>> {
+        let mut state = self.state.lock().unwrap();
+
+        let mut scope = Scope::new();
+        scope.push("command", to_dynamic(command).map_err(script_error)?);
+        scope.push_constant("vars", self.vars.clone());
+        scope.push("state", state.clone());
+
+        let data = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, ast)
+            .map_err(|e| format!("Script of {} mock failed: {}", kind, e))?;
+
+        if let Some(new_state) = scope.get_value::<Dynamic>("state") {
+            *state = new_state;
+        }
+
+        if data.is_unit() {
+            return Ok(None);
+        }
+
+        let data: Value = from_dynamic(&data).map_err(script_error)?;
+        let request: MainCommandParsable =
+            serde_json::from_value(json!({ "type": kind, "data": data })).map_err(|e| {
+                format!(
+                    "Script of {} mock returned an invalid response: {}",
+                    kind, e
+                )
+            })?;
+
+        Ok(Some(create_response_from_request(&request)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_secure_command(key: &str) -> MainCommand {
+        serde_json::from_value(
+            json!({ "type": "getSecure", "data": { "key": key, "value": null } }),
+        )
+        .unwrap()
+    }
+
+    fn mocks(mocks: Value) -> MockSet {
+        let mut set = MockSet::new(&Map::new(), Path::new(".")).unwrap();
+        for mock in mocks.as_array().unwrap() {
+            set.push(mock.clone()).unwrap();
+        }
+        set
+    }
+
+    /// Index of the mock answering a `getSecure` of `key` and the key of its response.
+    fn get_secure(set: &MockSet, key: &str) -> Option<(usize, String)> {
+        let command = get_secure_command(key);
+        let payload = serde_json::to_value(&command).unwrap()["data"].clone();
+        let reply = set.respond("pkg", &command, "getSecure", &payload)?;
+        match reply.response.unwrap() {
+            MainCommandResponse::GetSecure(data) => Some((reply.index, data.key)),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn responses_carry_the_requested_key() {
+        let set = mocks(json!([
+            { "type": "getSecure", "data": { "key": "accounts.*", "value": "token" } },
+            { "type": "getSecure", "default": true, "data": { "key": "", "value": null } }
+        ]));
+        assert_eq!(
+            get_secure(&set, "extensions.pkg.accounts.1"),
+            Some((0, "accounts.1".to_string()))
+        );
+        assert_eq!(
+            get_secure(&set, "extensions.pkg.session"),
+            Some((1, "session".to_string()))
+        );
+        assert_eq!(
+            get_secure(&set, "extensions.other.session"),
+            Some((1, "extensions.other.session".to_string()))
+        );
+    }
+
+    #[test]
+    fn default_mocks_only_answer_what_others_do_not() {
+        let set = mocks(json!([
+            { "type": "getSecure", "default": true, "data": { "key": "*", "value": null } },
+            { "type": "getSecure", "data": { "key": "session", "value": "abc" } }
+        ]));
+        assert_eq!(get_secure(&set, "extensions.pkg.session").unwrap().0, 1);
+        assert_eq!(get_secure(&set, "extensions.pkg.other").unwrap().0, 0);
+    }
+
+    #[test]
+    fn on_call_selects_calls_by_their_number() {
+        let set = mocks(json!([
+            { "type": "getSecure", "onCall": [2], "error": "expired" },
+            { "type": "getSecure", "data": { "key": "session", "value": "abc" } }
+        ]));
+        assert_eq!(get_secure(&set, "extensions.pkg.session").unwrap().0, 1);
+        let command = get_secure_command("extensions.pkg.session");
+        let reply = set
+            .respond("pkg", &command, "getSecure", &Value::Null)
+            .unwrap();
+        assert_eq!(reply.index, 0);
+        assert!(reply.response.is_err());
+        assert_eq!(get_secure(&set, "extensions.pkg.session").unwrap().0, 1);
+    }
+}
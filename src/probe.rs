@@ -0,0 +1,337 @@
+//! Collects the reports of modules instrumented by `wasm`. The instrumented code logs
+//! every report through the extism kernel, and `ProbeLayer` picks them out of the
+//! tracing events of the runtime.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{
+    EnvFilter,
+    layer::{Context, Layer, SubscriberExt},
+};
+use types::errors::Result;
+
+use crate::wasm::{
+    REPORT_CALL, REPORT_COUNT, REPORT_FUEL, REPORT_MEMORY, REPORT_MEMORY_LIMIT, REPORT_OUT_OF_FUEL,
+    REPORT_RETURN,
+};
+
+/// Start of the log line of a report, followed by `<token>:<kind><value>`, with the token
+/// as 8 and the value as 16 hex digits.
+pub(crate) const PROBE_PREFIX: &str = "moodriver-probe:";
+
+/// Report kind of the ping `install` logs to check that reports reach their probe.
+const REPORT_PING: u8 = b'p';
+
+lazy_static::lazy_static! {
+    static ref PROBES: Mutex<HashMap<u32, Arc<Mutex<Reports>>>> = Mutex::new(HashMap::new());
+}
+
+/// Resources used by the calls of exports of a metered module since `take_usage`.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Usage {
+    /// Instructions executed
+    pub(crate) fuel: u64,
+    /// Pages of linear memory when the usage started
+    pub(crate) start_pages: u64,
+    /// Pages of linear memory after the last call that returned
+    pub(crate) pages: Option<u64>,
+    /// Fuel of a call that ran out of it
+    pub(crate) out_of_fuel: Option<u64>,
+    /// Pages asked for by a `memory.grow` past the memory limit
+    pub(crate) memory_limit: Option<u64>,
+}
+
+/// How the calls of exports of a module instrumented with `calls` ended since `take_calls`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Calls {
+    /// Calls that returned zero, which extism exports return on success
+    pub(crate) succeeded: u64,
+    /// Calls that returned anything else, which extism exports return with an error
+    pub(crate) failed: u64,
+    /// Calls that never returned, as they trapped
+    pub(crate) trapped: u64,
+}
+
+/// What an instrumented module reported so far.
+#[derive(Debug, Default)]
+pub(crate) struct Reports {
+    /// How often every segment of code ran, by segment, see `wasm::CodeMap`
+    pub(crate) counts: BTreeMap<u32, u64>,
+    /// Whether the module reported anything
+    pub(crate) received: bool,
+    /// Whether the ping of `install` was received
+    pinged: bool,
+    usage: Usage,
+    calls: Calls,
+    /// Whether a call of an export started without returning yet. Calls of a module
+    /// never overlap, so one that starts while this is set follows a trap
+    calling: bool,
+    /// Last known pages of linear memory
+    pages: u64,
+}
+
+impl Reports {
+    fn record(&mut self, kind: u8, value: u64) {
+        if kind == REPORT_PING {
+            self.pinged = true;
+            return;
+        }
+        self.received = true;
+        match kind {
+            REPORT_COUNT => {
+                let count = self.counts.entry((value >> 32) as u32).or_default();
+                *count = count.saturating_add(value & u32::MAX as u64);
+            }
+            REPORT_FUEL => self.usage.fuel = self.usage.fuel.saturating_add(value),
+            REPORT_MEMORY => {
+                self.pages = value;
+                self.usage.pages = Some(value);
+            }
+            REPORT_OUT_OF_FUEL => {
+                self.usage.fuel = self.usage.fuel.saturating_add(value);
+                self.usage.out_of_fuel = Some(value);
+            }
+            REPORT_MEMORY_LIMIT => self.usage.memory_limit = Some(value),
+            REPORT_CALL => {
+                if self.calling {
+                    self.calls.trapped += 1;
+                }
+                self.calling = true;
+            }
+            REPORT_RETURN => {
+                self.calling = false;
+                if value == 0 {
+                    self.calls.succeeded += 1;
+                } else {
+                    self.calls.failed += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Sets the pages of linear memory the module is instantiated with.
+    pub(crate) fn set_initial_pages(&mut self, pages: u64) {
+        self.pages = pages;
+        self.usage.start_pages = pages;
+    }
+
+    /// Last known pages of linear memory.
+    pub(crate) fn pages(&self) -> u64 {
+        self.pages
+    }
+
+    /// Returns the usage since the last call and starts a new one.
+    pub(crate) fn take_usage(&mut self) -> Usage {
+        let usage = std::mem::take(&mut self.usage);
+        self.usage.start_pages = self.pages;
+        usage
+    }
+
+    /// Returns how the calls since the last call ended. A call still running is counted
+    /// as trapped, so only take them once no command is waiting for the module.
+    pub(crate) fn take_calls(&mut self) -> Calls {
+        let mut calls = std::mem::take(&mut self.calls);
+        if std::mem::take(&mut self.calling) {
+            calls.trapped += 1;
+        }
+        calls
+    }
+}
+
+/// Receives the reports of one instrumented module until dropped.
+pub(crate) struct Probe {
+    token: u32,
+    reports: Arc<Mutex<Reports>>,
+}
+
+impl Probe {
+    pub(crate) fn new() -> Self {
+        let mut probes = PROBES.lock().unwrap();
+        let token = loop {
+            let token = rand::random::<u32>();
+            if !probes.contains_key(&token) {
+                break token;
+            }
+        };
+        let reports = Arc::new(Mutex::new(Reports::default()));
+        probes.insert(token, reports.clone());
+        Self { token, reports }
+    }
+
+    pub(crate) fn token(&self) -> u32 {
+        self.token
+    }
+
+    pub(crate) fn reports(&self) -> MutexGuard<'_, Reports> {
+        self.reports.lock().unwrap()
+    }
+}
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        PROBES.lock().unwrap().remove(&self.token);
+    }
+}
+
+/// Records the report in a log message, if it is one.
+fn record(message: &str) {
+    let Some(start) = message.find(PROBE_PREFIX) else {
+        return;
+    };
+    let report = &message[start + PROBE_PREFIX.len()..];
+    let (Some(token), Some(kind), Some(value)) = (
+        report
+            .get(..8)
+            .and_then(|t| u32::from_str_radix(t, 16).ok()),
+        report.as_bytes().get(9),
+        report
+            .get(10..26)
+            .and_then(|v| u64::from_str_radix(v, 16).ok()),
+    ) else {
+        return;
+    };
+
+    let reports = PROBES.lock().unwrap().get(&token).cloned();
+    if let Some(reports) = reports {
+        reports.lock().unwrap().record(*kind, value);
+    }
+}
+
+/// Whether a formatted log line is a report, which is left out of the extension output.
+pub(crate) fn is_report(line: &[u8]) -> bool {
+    line.windows(PROBE_PREFIX.len())
+        .any(|w| w == PROBE_PREFIX.as_bytes())
+}
+
+#[derive(Default)]
+struct MessageVisitor(Option<String>);
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+/// Tracing layer that passes the reports of instrumented extensions to the run that
+/// loaded them. Runs install a subscriber with it unless one is already set. A test binary
+/// that sets its own subscriber must add this layer to it, with the `extism::pdk` target
+/// enabled at the info level, or runs that instrument the extension fail:
+///
+/// ```ignore
+/// tracing_subscriber::registry()
+///     .with(tracing_subscriber::fmt::layer())
+///     .with(moodriver::ProbeLayer)
+///     .init();
+/// ```
+pub struct ProbeLayer;
+
+impl<S: Subscriber> Layer<S> for ProbeLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if !event.metadata().target().starts_with("extism") {
+            return;
+        }
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        if let Some(message) = visitor.0 {
+            record(&message);
+        }
+    }
+}
+
+/// Installs a subscriber with only the `ProbeLayer`, unless one is already set, e.g. by
+/// the CLI or by the tests of the extension, and fails unless a report logged like the
+/// ones of the module reaches `probe`.
+pub(crate) fn install(probe: &Probe) -> Result<()> {
+    if !tracing::dispatcher::has_been_set() {
+        let subscriber = tracing_subscriber::registry()
+            .with(ProbeLayer.with_filter(EnvFilter::new("extism::pdk=info")));
+        let _ = tracing::subscriber::set_global_default(subscriber);
+    }
+
+    tracing::info!(
+        target: "extism::pdk",
+        "{}{:08x}:{}{:016x}",
+        PROBE_PREFIX,
+        probe.token,
+        REPORT_PING as char,
+        0
+    );
+    if probe.reports().pinged {
+        Ok(())
+    } else {
+        Err(
+            "The instrumented extension cannot report to moodriver, as a tracing subscriber \
+             without `moodriver::ProbeLayer` is set. Add the layer to it, with the \
+             `extism::pdk` target enabled at the info level"
+                .into(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_calls_that_trapped() {
+        let mut reports = Reports::default();
+        reports.record(REPORT_CALL, 3);
+        reports.record(REPORT_RETURN, 0);
+        reports.record(REPORT_CALL, 3);
+        reports.record(REPORT_RETURN, 1);
+        // Never returned, as the next call shows
+        reports.record(REPORT_CALL, 4);
+        reports.record(REPORT_CALL, 3);
+        reports.record(REPORT_RETURN, 0);
+        assert_eq!(
+            reports.take_calls(),
+            Calls {
+                succeeded: 2,
+                failed: 1,
+                trapped: 1,
+            }
+        );
+
+        reports.record(REPORT_CALL, 3);
+        assert_eq!(
+            reports.take_calls(),
+            Calls {
+                trapped: 1,
+                ..Calls::default()
+            }
+        );
+        assert_eq!(reports.take_calls(), Calls::default());
+    }
+
+    #[test]
+    fn reads_reports_from_log_messages() {
+        let probe = Probe::new();
+        record(&format!(
+            "{}{:08x}:{}{:016x}",
+            PROBE_PREFIX,
+            probe.token(),
+            REPORT_FUEL as char,
+            42
+        ));
+        record(&format!("{}{:08x}:f0000", PROBE_PREFIX, probe.token()));
+        record("unrelated");
+        assert_eq!(probe.reports().take_usage().fuel, 42);
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use colored::*;
+use rustyline::{
+    Context, Editor, Helper, completion::Completer, error::ReadlineError, highlight::Highlighter,
+    hint::Hinter, history::DefaultHistory, validate::Validator,
+};
+use serde_json::{Map, Value, json};
+use types::errors::{MoosyncError, Result};
+
+use crate::{
+    ValidCommand,
+    events::{EXTENSION_COMMANDS, EXTENSION_EXTRA_EVENTS},
+    format::{TraceCodec, TraceFormat},
+    host::Host,
+    manifest::validate_manifest,
+    mocks::MockSet,
+    reply_handler, start_extension,
+    wasm::Instrumentation,
+};
+
+const META_COMMANDS: &[&str] = &[":help", ":mocks", ":mock", ":unmock", ":save", ":quit"];
+
+const HELP: &str = "Commands:
+  <type> [data...]    Send an event or command, e.g. `seeked 30` or `requestedSearchResult \"foo\"`
+  :mocks              List the active request mocks
+  :mock <json>        Add a request mock, e.g. :mock {\"type\": \"getVolume\", \"data\": 50}
+  :unmock <index>     Remove a request mock
+  :save <path>        Save the session as a trace file, in the format of its extension
+  :help               Show this help
+  :quit               Exit";
+
+struct ReplHelper;
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        if prefix.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+
+        let candidates = EXTENSION_EXTRA_EVENTS
+            .iter()
+            .chain(EXTENSION_COMMANDS)
+            .chain(META_COMMANDS)
+            .filter(|c| c.starts_with(prefix))
+            .map(|c| c.to_string())
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// Loads the extension once and reads commands from the terminal until `:quit`.
+pub(crate) async fn run_repl(manifest_path: &Path, verbose: u8) -> Result<()> {
+    validate_manifest(manifest_path)?;
+
+    // Mocks can be edited while the extension is running. The raw values are kept so
+    // the session can be saved as a trace.
+    let mut mocks: Vec<Value> = Vec::new();
+    let host = Arc::new(Host::new(
+        MockSet::new(&Map::new(), Path::new("."))?,
+        None,
+        None,
+        true,
+    ));
+    let extension = start_extension(
+        manifest_path,
+        reply_handler(host.clone()),
+        verbose,
+        true,
+        Instrumentation::default(),
+    )
+    .await?;
+
+    println!("{}", "Type :help for a list of commands".cyan());
+
+    let mut editor: Editor<ReplHelper, DefaultHistory> =
+        Editor::new().map_err(|e| MoosyncError::String(e.to_string()))?;
+    editor.set_helper(Some(ReplHelper));
+
+    let mut recorded: Vec<Value> = Vec::new();
+    loop {
+        let line = match tokio::task::block_in_place(|| editor.readline("moodriver> ")) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.to_string().into()),
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+
+        let (head, rest) = line
+            .split_once(char::is_whitespace)
+            .map(|(h, r)| (h, r.trim()))
+            .unwrap_or((line, ""));
+
+        match head {
+            ":quit" | ":q" => break,
+            ":help" => println!("{}", HELP),
+            ":mocks" => {
+                if mocks.is_empty() {
+                    println!("No mocks defined");
+                }
+                for (i, mock) in mocks.iter().enumerate() {
+                    println!("[{}] {}", i, mock);
+                }
+            }
+            ":mock" => match add_mock(&host, &mut mocks, rest) {
+                Ok(_) => println!("{}", "Mock added".green()),
+                Err(e) => println!("{}", e.to_string().red()),
+            },
+            ":unmock" => match rest.parse::<usize>() {
+                Ok(i) if i < mocks.len() => {
+                    mocks.remove(i);
+                    host.mocks.write().unwrap().remove(i);
+                    println!("{}", "Mock removed".green());
+                }
+                _ => println!("{}", format!("Invalid mock index: {}", rest).red()),
+            },
+            ":save" => {
+                let trace = json!({
+                    "commands": recorded,
+                    "requests": mocks,
+                });
+                match save_trace(&PathBuf::from(rest), &trace) {
+                    Ok(_) => println!("{} {}", "Saved trace to".green(), rest.green()),
+                    Err(e) => println!("{}", e.to_string().red()),
+                }
+            }
+            _ => {
+                let command = match parse_command(head, rest, &extension.package_name) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        println!("{}", e.to_string().red());
+                        continue;
+                    }
+                };
+
+                let mut entry = serde_json::to_value(&command)?;
+                let sent = extension.send(command).await;
+                // Requests are already printed as they happen
+                host.take_calls();
+                match sent {
+                    Ok(resp) => {
+                        println!(
+                            "{} {}",
+                            "Response:".green(),
+                            serde_json::to_string_pretty(&resp).unwrap()
+                        );
+                        entry["expected"] = resp;
+                        recorded.push(entry);
+                    }
+                    Err(e) => println!("{} {}", "Error:".red(), e.to_string().red()),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn add_mock(host: &Host, mocks: &mut Vec<Value>, raw: &str) -> Result<()> {
+    let raw: Value = serde_json::from_str(raw)?;
+
+    host.mocks.write().unwrap().push(raw.clone())?;
+    mocks.push(raw);
+    Ok(())
+}
+
+/// Builds a command from a REPL line like `seeked 30`.
+///
+/// The arguments are tried as the `data` itself, then wrapped in an array, so both
+/// single values and tuples can be typed without brackets. Commands that only take a
+/// package name get the loaded extension's package name when no arguments are given.
+fn parse_command(kind: &str, args: &str, package_name: &str) -> Result<ValidCommand> {
+    let mut candidates = Vec::new();
+    if args.is_empty() {
+        candidates.push(json!({ "type": kind }));
+        candidates.push(json!({ "type": kind, "data": [] }));
+        candidates.push(json!({ "type": kind, "data": { "packageName": package_name } }));
+    } else if let Ok(data) = serde_json::from_str::<Value>(args) {
+        candidates.push(json!({ "type": kind, "data": data.clone() }));
+        candidates.push(json!({ "type": kind, "data": [data] }));
+    } else {
+        let data: Vec<Value> = args
+            .split_whitespace()
+            .map(|arg| serde_json::from_str(arg).unwrap_or_else(|_| Value::String(arg.to_string())))
+            .collect();
+        candidates.push(json!({ "type": kind, "data": data }));
+    }
+
+    let mut last_error = None;
+    for candidate in candidates {
+        match serde_json::from_value(candidate) {
+            Ok(command) => return Ok(command),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(format!(
+        "Could not parse command '{} {}': {}",
+        kind,
+        args,
+        last_error.map(|e| e.to_string()).unwrap_or_default()
+    )
+    .into())
+}
+
+fn save_trace(path: &Path, trace: &Value) -> Result<()> {
+    if path.as_os_str().is_empty() {
+        return Err("Missing path to save the trace to".into());
+    }
+
+    let format = TraceFormat::from_path(path).ok_or_else(|| {
+        MoosyncError::String(format!("Unsupported trace file extension: {:?}", path))
+    })?;
+    let contents = format.serialize(trace)?;
+
+    fs::write(path, contents).map_err(|e| MoosyncError::String(e.to_string()))?;
+    Ok(())
+}
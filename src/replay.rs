@@ -0,0 +1,119 @@
+use std::{path::Path, sync::Arc};
+
+use types::errors::{MoosyncError, Result};
+
+use crate::{
+    CommandWrapper, Extension, HostCall, describe_command, describe_send_error, filter::Selection,
+    host::Host, inputs::InteractiveInputs, mocks::MockSet, parse_test_case, reply_handler,
+    resources::pages_to_mb, start_extension, wasm::Instrumentation,
+};
+
+/// The commands of a trace, replayed over and over against a single loaded extension by
+/// `bench` and `soak`. Responses are not checked.
+pub(crate) struct Replay {
+    extension: Extension,
+    host: Arc<Host>,
+    /// Selected commands of the trace, with their inputs resolved
+    pub(crate) commands: Vec<CommandWrapper>,
+    teardown: Vec<CommandWrapper>,
+}
+
+fn resolve(command: &CommandWrapper) -> Result<CommandWrapper> {
+    let mut command = command.clone();
+    InteractiveInputs::default().resolve(&mut command)?;
+    Ok(command)
+}
+
+impl Replay {
+    /// Loads the extension with the request mocks of the first case of `trace` and runs
+    /// its setup commands.
+    pub(crate) async fn load(
+        manifest_path: &Path,
+        trace: &Path,
+        selection: &Selection,
+        verbose: u8,
+        instrumentation: Instrumentation,
+    ) -> Result<Self> {
+        let run = parse_test_case(trace)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| MoosyncError::String("Trace has no cases".into()))?;
+        let selected = selection.select(&run);
+
+        let commands = run
+            .test_case
+            .commands
+            .iter()
+            .zip(selected)
+            .filter(|(_, selected)| *selected)
+            .map(|(command, _)| resolve(command))
+            .collect::<Result<Vec<_>>>()?;
+        if commands.is_empty() {
+            return Err("No commands of the trace were selected".into());
+        }
+
+        let mut mocks = MockSet::new(&run.vars, &run.dir)?;
+        for mock in run.test_case.requests {
+            mocks.push(mock)?;
+        }
+        let host = Arc::new(Host::new(mocks, None, None, false));
+        let extension = start_extension(
+            manifest_path,
+            reply_handler(host.clone()),
+            verbose,
+            true,
+            instrumentation,
+        )
+        .await?;
+
+        let replay = Self {
+            extension,
+            host,
+            commands,
+            teardown: run
+                .test_case
+                .teardown
+                .iter()
+                .map(resolve)
+                .collect::<Result<Vec<_>>>()?,
+        };
+        for command in &run.test_case.setup {
+            replay.send(&resolve(command)?).await?;
+        }
+
+        Ok(replay)
+    }
+
+    pub(crate) async fn send(&self, command: &CommandWrapper) -> Result<()> {
+        self.extension
+            .send(command.command.clone())
+            .await
+            .map_err(|e| {
+                MoosyncError::String(format!(
+                    "{} failed:\n{}",
+                    describe_command(command),
+                    describe_send_error(e)
+                ))
+            })?;
+        Ok(())
+    }
+
+    /// Returns the host requests made since the last call.
+    pub(crate) fn take_calls(&self) -> Vec<HostCall> {
+        self.host.take_calls()
+    }
+
+    /// Size of the linear memory of the extension in megabytes, as last reported by its
+    /// metered module.
+    pub(crate) fn memory_mb(&self) -> Result<f64> {
+        Ok(pages_to_mb(self.extension.reports()?.pages()))
+    }
+
+    /// Runs the teardown commands of the trace.
+    pub(crate) async fn finish(self) -> Result<()> {
+        for command in &self.teardown {
+            self.send(command).await?;
+        }
+        Ok(())
+    }
+}
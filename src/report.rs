@@ -0,0 +1,249 @@
+use std::{collections::BTreeMap, fmt};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::MemoryUsage;
+
+/// The phase of a trace a command belongs to.
+/// Setup and teardown commands only have their responses checked when `expected` is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Phase {
+    Setup,
+    Test,
+    Teardown,
+}
+
+impl Phase {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Phase::Setup => "Setup",
+            Phase::Test => "Command",
+            Phase::Teardown => "Teardown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CommandStatus {
+    Passed,
+    Failed,
+    Skipped,
+}
+
+/// The outcome of a single command of a trace.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandReport {
+    pub phase: Phase,
+    /// Position of the command within its phase
+    pub index: usize,
+    pub name: String,
+    /// Type of the command, e.g. `requestedSearchResult`
+    pub kind: String,
+    pub status: CommandStatus,
+    /// Response returned by the extension, if the command was sent
+    pub response: Option<Value>,
+    pub error: Option<String>,
+    pub duration_ms: f64,
+    /// Linear memory of the extension while the command ran, shared by the commands of a
+    /// parallel group. Only measured when the trace sets a limit
+    pub memory: Option<MemoryUsage>,
+    /// Wasm instructions executed while the command ran, shared by the commands of a
+    /// parallel group. Only measured when the trace sets a limit
+    pub fuel: Option<u64>,
+    /// Position of the parallel group the command was sent in, among the commands of its
+    /// phase
+    pub parallel: Option<usize>,
+    /// Host requests the extension made while handling the command. The requests made while
+    /// a parallel group runs cannot be told apart, so the group is reported as one unit:
+    /// they are all on the report of its first command that was sent
+    pub host_calls: Vec<HostCall>,
+}
+
+/// A host request made by the extension, e.g. `getSecure`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostCall {
+    /// Type of the request, as written in the `requests` of a trace
+    pub kind: String,
+    /// Payload of the request
+    pub data: Value,
+    /// Simulated latency, from `delayMs` or `--host-latency`
+    pub delay_ms: f64,
+    /// Time taken to answer the request, including the delay
+    pub duration_ms: f64,
+    pub error: Option<String>,
+    pub answered_by: AnsweredBy,
+}
+
+/// What answered a host request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AnsweredBy {
+    /// The [`HostResponder`](crate::HostResponder) of the run
+    Responder,
+    /// The mock at this position of [`MockCoverage::mocks`]
+    Mock(usize),
+    /// The default response, since no mock answered
+    Default,
+}
+
+/// How often a request mock was used during a run.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MockUsage {
+    /// Type of the mocked request
+    pub kind: String,
+    /// Short description of the mock, e.g. `getSecure session`
+    pub label: String,
+    /// Number of requests the mock answered
+    pub hits: usize,
+}
+
+/// Which request mocks a run used, and which host requests no mock answered.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MockCoverage {
+    /// Mocks of the trace followed by the ones added with [`Driver::mock`](crate::Driver::mock)
+    pub mocks: Vec<MockUsage>,
+    /// Number of host requests made, by type
+    pub calls: BTreeMap<String, usize>,
+    /// Number of host requests answered with a default response, by type
+    pub fallbacks: BTreeMap<String, usize>,
+}
+
+impl MockCoverage {
+    pub(crate) fn new<'a>(
+        labels: Vec<(String, String)>,
+        calls: impl IntoIterator<Item = &'a HostCall>,
+    ) -> Self {
+        let mut coverage = Self {
+            mocks: labels
+                .into_iter()
+                .map(|(kind, label)| MockUsage {
+                    kind,
+                    label,
+                    hits: 0,
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        for call in calls {
+            *coverage.calls.entry(call.kind.clone()).or_default() += 1;
+            match call.answered_by {
+                AnsweredBy::Mock(index) => {
+                    if let Some(mock) = coverage.mocks.get_mut(index) {
+                        mock.hits += 1;
+                    }
+                }
+                AnsweredBy::Default => {
+                    *coverage.fallbacks.entry(call.kind.clone()).or_default() += 1;
+                }
+                AnsweredBy::Responder => {}
+            }
+        }
+
+        coverage
+    }
+
+    /// Mocks that answered no request.
+    pub fn unused(&self) -> impl Iterator<Item = &MockUsage> {
+        self.mocks.iter().filter(|m| m.hits == 0)
+    }
+}
+
+impl fmt::Display for MockCoverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, mock) in self.mocks.iter().enumerate() {
+            match mock.hits {
+                0 => writeln!(f, "  [{}] {}: unused", i + 1, mock.label)?,
+                hits => writeln!(f, "  [{}] {}: {} calls", i + 1, mock.label, hits)?,
+            }
+        }
+
+        if !self.fallbacks.is_empty() {
+            let fallbacks = self
+                .fallbacks
+                .iter()
+                .map(|(kind, count)| format!("{} x{}", kind, count))
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(f, "  Default responses: {}", fallbacks)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The outcome of a single run of a trace. Traces with `cases` or `matrix` produce one
+/// report per case.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceReport {
+    pub name: String,
+    pub passed: bool,
+    /// Whether no command of the trace was selected, in which case it was not run
+    pub skipped: bool,
+    /// Host requests the extension made while it was loading
+    pub startup_host_calls: Vec<HostCall>,
+    pub commands: Vec<CommandReport>,
+    /// Usage of the request mocks during the run
+    pub coverage: MockCoverage,
+    /// Host call assertions that were not checked, as skipped or unsent commands made the
+    /// recorded requests incomplete
+    pub unchecked_assertions: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl TraceReport {
+    pub(crate) fn skipped(name: String) -> Self {
+        Self {
+            name,
+            passed: true,
+            skipped: true,
+            startup_host_calls: Vec::new(),
+            commands: Vec::new(),
+            coverage: MockCoverage::default(),
+            unchecked_assertions: Vec::new(),
+            error: None,
+        }
+    }
+}
+
+impl fmt::Display for TraceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.skipped {
+            "skipped"
+        } else if self.passed {
+            "passed"
+        } else {
+            "failed"
+        };
+        writeln!(f, "{}: {}", self.name, status)?;
+
+        for command in &self.commands {
+            writeln!(
+                f,
+                "  {} [{}] {}: {:?}",
+                command.phase.label(),
+                command.index + 1,
+                command.name,
+                command.status
+            )?;
+        }
+
+        for assertion in &self.unchecked_assertions {
+            writeln!(f, "  Not checked: {}", assertion)?;
+        }
+
+        if let Some(error) = &self.error {
+            writeln!(f, "\n{}", error)?;
+        }
+
+        Ok(())
+    }
+}
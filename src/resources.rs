@@ -0,0 +1,76 @@
+use serde::Serialize;
+use types::errors::Result;
+
+use crate::{LIMIT_ERROR, probe::Usage};
+
+/// Bytes in a page of wasm linear memory.
+const PAGE_SIZE: f64 = 64.0 * 1024.0;
+
+const MB: f64 = 1024.0 * 1024.0;
+
+/// Linear memory of the extension's wasm module while a command ran.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryUsage {
+    /// Size of the linear memory once the command finished, in megabytes. Linear memory
+    /// never shrinks, so this is also the highest it reached
+    pub peak_mb: f64,
+    /// Growth of the linear memory during the command, in megabytes
+    pub growth_mb: f64,
+}
+
+impl MemoryUsage {
+    pub(crate) fn new(usage: &Usage) -> Self {
+        let pages = usage.pages.unwrap_or(usage.start_pages);
+        Self {
+            peak_mb: pages_to_mb(pages),
+            growth_mb: pages_to_mb(pages) - pages_to_mb(usage.start_pages),
+        }
+    }
+}
+
+pub(crate) fn pages_to_mb(pages: u64) -> f64 {
+    pages as f64 * PAGE_SIZE / MB
+}
+
+/// Whole pages that fit in `mb` megabytes.
+pub(crate) fn mb_to_pages(mb: f64) -> u64 {
+    (mb * MB / PAGE_SIZE).floor() as u64
+}
+
+/// Fails if the usage of a command is over its `maxMemoryMb` or `maxFuel`, or if the
+/// extension was stopped for going over the highest limit of the trace, which applies to
+/// every command.
+pub(crate) fn check_limits(
+    usage: &Usage,
+    max_memory_mb: Option<f64>,
+    max_fuel: Option<u64>,
+) -> Result<()> {
+    let memory_mb = pages_to_mb(usage.pages.unwrap_or(usage.start_pages));
+    let error = if let Some(pages) = usage.memory_limit {
+        format!(
+            "{} the extension was stopped as its linear memory would grow to {:.1}MB, over the highest maxMemoryMb of the trace",
+            LIMIT_ERROR,
+            pages_to_mb(pages)
+        )
+    } else if let Some(fuel) = usage.out_of_fuel {
+        format!(
+            "{} the extension was stopped after {} instructions, the highest maxFuel of the trace",
+            LIMIT_ERROR, fuel
+        )
+    } else if let Some(limit) = max_memory_mb.filter(|limit| memory_mb > *limit) {
+        format!(
+            "{} linear memory grew to {:.1}MB, over the maxMemoryMb of {}MB",
+            LIMIT_ERROR, memory_mb, limit
+        )
+    } else if let Some(limit) = max_fuel.filter(|limit| usage.fuel > *limit) {
+        format!(
+            "{} executed {} instructions, over the maxFuel of {}",
+            LIMIT_ERROR, usage.fuel, limit
+        )
+    } else {
+        return Ok(());
+    };
+
+    Err(error.into())
+}
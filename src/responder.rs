@@ -0,0 +1,98 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use types::{
+    errors::{MoosyncError, Result},
+    extensions::{MainCommand, MainCommandResponse},
+};
+
+/// Answers host requests made by an extension, e.g. to compute a `GetSong` reply from the
+/// request's query or to simulate a failure.
+///
+/// The responder is asked first. Requests it does not answer fall back to the `requests`
+/// of the trace, and then to a default response.
+pub trait HostResponder: Send + Sync {
+    /// Returns the response to a host request, or `None` to answer it from the trace.
+    fn respond(
+        &self,
+        package_name: &str,
+        command: &MainCommand,
+    ) -> Option<Result<MainCommandResponse>>;
+}
+
+impl<F> HostResponder for F
+where
+    F: Fn(&str, &MainCommand) -> Option<Result<MainCommandResponse>> + Send + Sync,
+{
+    fn respond(
+        &self,
+        package_name: &str,
+        command: &MainCommand,
+    ) -> Option<Result<MainCommandResponse>> {
+        self(package_name, command)
+    }
+}
+
+/// Named host responders that can be selected with `--responder`.
+///
+/// ```no_run
+/// use std::process::ExitCode;
+///
+/// use moodriver::{
+///     HostResponder, MainCommand, MainCommandResponse, MoosyncError, ResponderRegistry,
+/// };
+///
+/// struct FixedVolume;
+///
+/// impl HostResponder for FixedVolume {
+///     fn respond(
+///         &self,
+///         _package_name: &str,
+///         command: &MainCommand,
+///     ) -> Option<Result<MainCommandResponse, MoosyncError>> {
+///         match command {
+///             MainCommand::GetVolume() => Some(Ok(MainCommandResponse::GetVolume(50.0))),
+///             _ => None,
+///         }
+///     }
+/// }
+///
+/// #[tokio::main]
+/// async fn main() -> ExitCode {
+///     let registry = ResponderRegistry::new().register("fixed-volume", FixedVolume);
+///     moodriver::cli::main_with(registry).await
+/// }
+/// ```
+#[derive(Default, Clone)]
+pub struct ResponderRegistry {
+    responders: BTreeMap<String, Arc<dyn HostResponder>>,
+}
+
+impl ResponderRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(mut self, name: &str, responder: impl HostResponder + 'static) -> Self {
+        self.responders
+            .insert(name.to_string(), Arc::new(responder));
+        self
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<Arc<dyn HostResponder>> {
+        self.responders.get(name).cloned().ok_or_else(|| {
+            let available = if self.responders.is_empty() {
+                "none".to_string()
+            } else {
+                self.responders
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            MoosyncError::String(format!(
+                "Unknown host responder '{}', available: {}",
+                name, available
+            ))
+        })
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use colored::*;
+use types::errors::Result;
+
+use crate::{
+    filter::Selection,
+    replay::Replay,
+    wasm::{Instrumentation, Meter},
+};
+
+/// Iterations run when neither a count nor a duration is given.
+const DEFAULT_ITERATIONS: usize = 1000;
+
+/// Number of windows the samples are split into to look for growth.
+const WINDOWS: usize = 5;
+
+/// How often progress is printed, in iterations.
+const PROGRESS_EVERY: usize = 100;
+
+/// Options of `moodriver soak`.
+pub(crate) struct SoakOptions {
+    pub(crate) manifest_path: PathBuf,
+    pub(crate) trace: PathBuf,
+    pub(crate) iterations: Option<usize>,
+    pub(crate) duration: Option<Duration>,
+    pub(crate) selection: Selection,
+    pub(crate) verbose: u8,
+    /// Linear memory growth below this is not reported as a leak, in megabytes
+    pub(crate) max_growth_mb: f64,
+}
+
+/// Measurements taken after every iteration.
+struct Sample {
+    /// Size of the linear memory of the extension
+    memory_mb: f64,
+    /// Host requests made during the iteration
+    host_calls: usize,
+}
+
+/// Returns how much `values` grew if they grew steadily. The values are split into
+/// `WINDOWS` windows, the last one taking the remainder, and growth is steady if the
+/// median of no window is below the one before, the last is more than `min_growth` above
+/// the first, and it is still above the middle one. A leak may plateau for a while, but
+/// growth that stops early, like caches warming up, is not steady. `None` if there are too
+/// few values to tell.
+fn steady_growth(values: &[f64], min_growth: f64) -> Option<f64> {
+    let size = values.len() / WINDOWS;
+    if size < 2 {
+        return None;
+    }
+
+    let medians: Vec<f64> = (0..WINDOWS)
+        .map(|i| {
+            let end = if i + 1 == WINDOWS {
+                values.len()
+            } else {
+                (i + 1) * size
+            };
+            let mut window = values[i * size..end].to_vec();
+            window.sort_by(f64::total_cmp);
+            window[window.len() / 2]
+        })
+        .collect();
+
+    let growth = medians[WINDOWS - 1] - medians[0];
+    let steady =
+        medians.windows(2).all(|w| w[1] >= w[0]) && medians[WINDOWS - 1] > medians[WINDOWS / 2];
+    (steady && growth > min_growth).then_some(growth)
+}
+
+/// Replays the selected commands of a trace for a number of iterations or a duration, and
+/// fails if the linear memory of the extension or the host requests per iteration keep
+/// growing.
+pub(crate) async fn run_soak(opts: &SoakOptions) -> Result<()> {
+    // Metering without limits, for the reports of the size of the linear memory
+    let replay = Replay::load(
+        &opts.manifest_path,
+        &opts.trace,
+        &opts.selection,
+        opts.verbose,
+        Instrumentation {
+            coverage: false,
+            meter: Some(Meter::default()),
+            calls: false,
+        },
+    )
+    .await?;
+
+    let iterations = match (opts.iterations, opts.duration) {
+        (None, None) => Some(DEFAULT_ITERATIONS),
+        (iterations, _) => iterations,
+    };
+    let started = Instant::now();
+    let done = |iteration: usize| {
+        iterations.is_some_and(|n| iteration >= n)
+            || opts.duration.is_some_and(|d| started.elapsed() >= d)
+    };
+
+    println!("{} {} commands", "Soaking".blue(), replay.commands.len());
+
+    let mut samples = Vec::new();
+    while !done(samples.len()) {
+        for command in &replay.commands {
+            replay
+                .send(command)
+                .await
+                .map_err(|e| format!("Iteration {}: {}", samples.len() + 1, e))?;
+        }
+
+        let sample = Sample {
+            memory_mb: replay
+                .memory_mb()
+                .map_err(|e| format!("Linear memory of the extension is unknown: {}", e))?,
+            host_calls: replay.take_calls().len(),
+        };
+        if (samples.len() + 1) % PROGRESS_EVERY == 0 {
+            println!(
+                "{}",
+                format!(
+                    "Iteration {} after {:.1}s: {:.1}MB of linear memory, {} host calls",
+                    samples.len() + 1,
+                    started.elapsed().as_secs_f64(),
+                    sample.memory_mb,
+                    sample.host_calls
+                )
+                .dimmed()
+            );
+        }
+        samples.push(sample);
+    }
+    replay.finish().await?;
+
+    println!(
+        "\n{} {} iterations in {:.1}s",
+        "Completed".blue(),
+        samples.len(),
+        started.elapsed().as_secs_f64()
+    );
+
+    let mut leaks = Vec::new();
+
+    let memory: Vec<f64> = samples.iter().map(|s| s.memory_mb).collect();
+    if let (Some(first), Some(last)) = (memory.first(), memory.last()) {
+        println!("Linear memory: {:.1}MB -> {:.1}MB", first, last);
+    }
+    if let Some(growth) = steady_growth(&memory, opts.max_growth_mb) {
+        leaks.push(format!(
+            "Linear memory grew steadily by {:.1}MB, the extension may be leaking memory",
+            growth
+        ));
+    }
+
+    let host_calls: Vec<f64> = samples.iter().map(|s| s.host_calls as f64).collect();
+    if let (Some(first), Some(last)) = (host_calls.first(), host_calls.last()) {
+        println!("Host calls per iteration: {} -> {}", first, last);
+    }
+    if let Some(growth) = steady_growth(&host_calls, 0.0) {
+        leaks.push(format!(
+            "Host calls per iteration grew steadily by {}, the extension may be accumulating state",
+            growth
+        ));
+    }
+
+    if samples.len() < WINDOWS * 2 {
+        println!(
+            "{}",
+            format!(
+                "Too few iterations to detect growth, at least {} are needed",
+                WINDOWS * 2
+            )
+            .yellow()
+        );
+    }
+
+    if leaks.is_empty() {
+        println!("{}", "No steady growth detected".green());
+        Ok(())
+    } else {
+        Err(leaks.join("\n").into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp(steps: &[f64], repeat: usize) -> Vec<f64> {
+        steps
+            .iter()
+            .flat_map(|step| std::iter::repeat_n(*step, repeat))
+            .collect()
+    }
+
+    #[test]
+    fn finds_growth_that_keeps_going() {
+        let values = (0..100).map(f64::from).collect::<Vec<_>>();
+        assert_eq!(steady_growth(&values, 10.0), Some(80.0));
+        assert_eq!(steady_growth(&values, 80.0), None);
+    }
+
+    #[test]
+    fn splits_into_windows_with_the_remainder_in_the_last() {
+        // Windows of 2, 2, 2, 2 and 6 values
+        let values = ramp(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0], 2);
+        assert_eq!(steady_growth(&values, 0.0), Some(5.0));
+    }
+
+    #[test]
+    fn finds_leaks_that_plateau() {
+        let values = ramp(&[1.0, 2.0, 2.0, 3.0, 4.0], 10);
+        assert_eq!(steady_growth(&values, 0.0), Some(3.0));
+    }
+
+    #[test]
+    fn ignores_growth_that_stops() {
+        let warm_up = ramp(&[1.0, 5.0, 5.0, 5.0, 5.0], 10);
+        assert_eq!(steady_growth(&warm_up, 0.0), None);
+
+        let dip = ramp(&[1.0, 3.0, 2.0, 4.0, 5.0], 10);
+        assert_eq!(steady_growth(&dip, 0.0), None);
+    }
+
+    #[test]
+    fn needs_two_values_per_window() {
+        let values = (0..9).map(f64::from).collect::<Vec<_>>();
+        assert_eq!(steady_growth(&values, 0.0), None);
+        assert_eq!(steady_growth(&[], 0.0), None);
+    }
+}
@@ -0,0 +1,40 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use types::errors::Result;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory under the system temp dir, unique to this process, that is removed when
+/// dropped. Extensions loaded at the same time, e.g. by tests generated with
+/// `trace_test!`, each get their own.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Result<Self> {
+        let path = env::temp_dir().join(format!(
+            "moodriver-{}-{}",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        // Left over by an earlier process with the same id
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        Ok(Self(path))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,8 +1,11 @@
 use std::io::{self, Write};
 use std::sync::{Arc, Mutex};
 use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{self, EnvFilter};
 
+use crate::probe::{ProbeLayer, is_report};
+
 lazy_static::lazy_static! {
     static ref LOG_BUFFER: Arc<Mutex<Vec<u8>>> = Arc::new(Mutex::new(Vec::new()));
 }
@@ -23,6 +26,9 @@ impl<'a> MakeWriter<'a> for MemoryWriter {
 
 impl Write for MemoryWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if is_report(buf) {
+            return Ok(buf.len());
+        }
         let mut data = self.buffer.lock().unwrap();
         data.extend_from_slice(buf);
         Ok(buf.len())
@@ -33,6 +39,30 @@ impl Write for MemoryWriter {
     }
 }
 
+/// Writes to stdout, leaving out the reports of instrumented modules.
+struct StdoutWriter;
+
+impl<'a> MakeWriter<'a> for StdoutWriter {
+    type Writer = StdoutWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        StdoutWriter
+    }
+}
+
+impl Write for StdoutWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if is_report(buf) {
+            return Ok(buf.len());
+        }
+        io::stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
 pub(crate) fn create_verbose_log(verbosity: u8) {
     let level = if verbosity == 1 {
         "extism::pdk=debug"
@@ -41,7 +71,9 @@ pub(crate) fn create_verbose_log(verbosity: u8) {
     };
     let subscriber = tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::new(level))
-        .finish();
+        .with_writer(StdoutWriter)
+        .finish()
+        .with(ProbeLayer);
 
     tracing::subscriber::set_global_default(subscriber)
         .expect("Failed to set global default subscriber");
@@ -55,7 +87,8 @@ pub(crate) fn create_log_buffer() {
     let subscriber = tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::new("extism::pdk=debug"))
         .with_writer(writer)
-        .finish();
+        .finish()
+        .with(ProbeLayer);
 
     tracing::subscriber::set_global_default(subscriber)
         .expect("Failed to set global default subscriber");
@@ -1,6 +1,7 @@
 use colored::Colorize;
 use difference::{Changeset, Difference};
-use serde_json::Value;
+use serde_json::{Map, Value};
+use types::errors::Result;
 
 #[derive(Clone)]
 enum PathSegment {
@@ -144,3 +145,145 @@ pub(crate) fn pretty_print_diff(expected: &str, received: &str) -> String {
 
     ret
 }
+
+/// Replaces `${name}` placeholders in every string inside `value` with the bound variable.
+/// A string that consists of a single placeholder is replaced by the raw variable value,
+/// so numbers, booleans and objects keep their type.
+pub(crate) fn substitute_vars(value: &mut Value, vars: &Map<String, Value>) {
+    match value {
+        Value::String(s) => {
+            let whole = s
+                .strip_prefix("${")
+                .and_then(|rest| rest.strip_suffix('}'))
+                .and_then(|name| vars.get(name));
+            if let Some(var) = whole {
+                *value = var.clone();
+                return;
+            }
+
+            for (name, var) in vars {
+                let placeholder = format!("${{{}}}", name);
+                if s.contains(&placeholder) {
+                    let replacement = match var {
+                        Value::String(v) => v.clone(),
+                        other => other.to_string(),
+                    };
+                    *s = s.replace(&placeholder, &replacement);
+                }
+            }
+        }
+        Value::Object(map) => {
+            for v in map.values_mut() {
+                substitute_vars(v, vars);
+            }
+        }
+        Value::Array(arr) => {
+            for v in arr.iter_mut() {
+                substitute_vars(v, vars);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Whether every field of `subset` is present in `value` with the same value. Arrays match
+/// when each element of `subset` is a subset of the element at the same position.
+pub(crate) fn is_subset(subset: &Value, value: &Value) -> bool {
+    match (subset, value) {
+        (Value::Object(sub), Value::Object(map)) => sub
+            .iter()
+            .all(|(k, v)| map.get(k).is_some_and(|m| is_subset(v, m))),
+        (Value::Array(sub), Value::Array(arr)) => {
+            sub.len() <= arr.len() && sub.iter().zip(arr).all(|(s, v)| is_subset(s, v))
+        }
+        _ => subset == value,
+    }
+}
+
+/// Expands a `matrix` of `variable -> [values]` into the cartesian product of all bindings.
+pub(crate) fn expand_matrix(matrix: &Map<String, Value>) -> Result<Vec<Map<String, Value>>> {
+    let mut combinations = vec![Map::new()];
+
+    for (name, values) in matrix {
+        let values = values
+            .as_array()
+            .ok_or_else(|| format!("Matrix variable '{}' must be an array", name))?;
+        if values.is_empty() {
+            return Err(format!("Matrix variable '{}' has no values", name).into());
+        }
+
+        let mut expanded = Vec::with_capacity(combinations.len() * values.len());
+        for combination in &combinations {
+            for value in values {
+                let mut combination = combination.clone();
+                combination.insert(name.clone(), value.clone());
+                expanded.push(combination);
+            }
+        }
+        combinations = expanded;
+    }
+
+    Ok(combinations)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn vars(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn substitute_vars_keeps_the_type_of_whole_placeholders() {
+        let mut value =
+            json!({ "limit": "${limit}", "tags": ["${tag}"], "nested": { "on": "${on}" } });
+        substitute_vars(
+            &mut value,
+            &vars(json!({ "limit": 10, "tag": "rock", "on": true })),
+        );
+        assert_eq!(
+            value,
+            json!({ "limit": 10, "tags": ["rock"], "nested": { "on": true } })
+        );
+    }
+
+    #[test]
+    fn substitute_vars_formats_placeholders_inside_strings() {
+        let mut value = json!("${artist} - ${limit} songs, ${artist}");
+        substitute_vars(&mut value, &vars(json!({ "artist": "Queen", "limit": 3 })));
+        assert_eq!(value, json!("Queen - 3 songs, Queen"));
+    }
+
+    #[test]
+    fn substitute_vars_leaves_unknown_placeholders() {
+        let mut value = json!(["${missing}", "a ${missing} b"]);
+        substitute_vars(&mut value, &vars(json!({ "other": 1 })));
+        assert_eq!(value, json!(["${missing}", "a ${missing} b"]));
+    }
+
+    #[test]
+    fn expand_matrix_builds_every_combination() {
+        let combinations =
+            expand_matrix(&vars(json!({ "a": [1, 2], "b": ["x", "y", "z"] }))).unwrap();
+        assert_eq!(combinations.len(), 6);
+        for a in [1, 2] {
+            for b in ["x", "y", "z"] {
+                assert!(combinations.contains(&vars(json!({ "a": a, "b": b }))));
+            }
+        }
+    }
+
+    #[test]
+    fn expand_matrix_of_nothing_is_one_empty_binding() {
+        assert_eq!(expand_matrix(&Map::new()).unwrap(), vec![Map::new()]);
+    }
+
+    #[test]
+    fn expand_matrix_rejects_empty_and_non_array_axes() {
+        assert!(expand_matrix(&vars(json!({ "a": [1], "b": [] }))).is_err());
+        assert!(expand_matrix(&vars(json!({ "a": 1 }))).is_err());
+    }
+}
@@ -0,0 +1,1244 @@
+//! Rewrites the wasm module of an extension before it is loaded, so that it reports what
+//! happens inside it. Reports are written as log lines through the extism kernel, see
+//! `probe`, so no change to the runtime is needed.
+
+use std::{collections::BTreeMap, convert::Infallible, ops::Range};
+
+use types::errors::{MoosyncError, Result};
+use wasm_encoder::{
+    BlockType, CodeSection, ConstExpr, EntityType, ExportKind, ExportSection, Function,
+    FunctionSection, GlobalSection, GlobalType, ImportSection, InstructionSink, MemorySection,
+    Module, SectionId, TypeSection, ValType,
+    reencode::{Error as ReencodeError, Reencode, utils},
+};
+use wasmparser::{
+    CompositeInnerType, Encoding, ExternalKind, FunctionBody, KnownCustom, MemoryType, Name,
+    Operator, Parser, Payload, TypeRef,
+};
+
+use crate::{probe::PROBE_PREFIX, resources::pages_to_mb};
+
+/// Kernel functions of the extism runtime called by the instrumentation, with the index of
+/// their type in `ADDED_TYPES`. They are imported again even if the module already imports
+/// them, to keep the code simple.
+const KERNEL_MODULE: &str = "extism:host/env";
+const KERNEL_IMPORTS: [(&str, u32); 4] =
+    [("alloc", 0), ("store_u8", 1), ("log_info", 2), ("free", 2)];
+
+/// Types of the functions added, appended to the types of the module.
+const ADDED_TYPES: [(&[ValType], &[ValType]); 5] = [
+    (&[ValType::I64], &[ValType::I64]),
+    (&[ValType::I64, ValType::I32], &[]),
+    (&[ValType::I64], &[]),
+    (&[ValType::I32, ValType::I64], &[]),
+    (&[], &[]),
+];
+const CHARGE_TYPE: u32 = 2;
+const REPORT_TYPE: u32 = 3;
+const FLUSH_TYPE: u32 = 4;
+
+/// Order in which the known sections must appear in a module.
+const SECTION_ORDER: [SectionId; 13] = [
+    SectionId::Type,
+    SectionId::Import,
+    SectionId::Function,
+    SectionId::Table,
+    SectionId::Memory,
+    SectionId::Tag,
+    SectionId::Global,
+    SectionId::Export,
+    SectionId::Start,
+    SectionId::Element,
+    SectionId::DataCount,
+    SectionId::Code,
+    SectionId::Data,
+];
+
+/// Report kind of how often a segment ran since it was last reported, with the index of
+/// the segment in the upper and the count in the lower 32 bits of the value.
+pub(crate) const REPORT_COUNT: u8 = b'n';
+/// Report kind of the instructions executed by a call of an export.
+pub(crate) const REPORT_FUEL: u8 = b'f';
+/// Report kind of the pages of linear memory after a call of an export.
+pub(crate) const REPORT_MEMORY: u8 = b'm';
+/// Report kind of a call of an export starting, with the index of the export.
+pub(crate) const REPORT_CALL: u8 = b'c';
+/// Report kind of a call of an export returning, with the `i32` it returned, or zero for
+/// exports with other results. A call that traps never reports this.
+pub(crate) const REPORT_RETURN: u8 = b'r';
+/// Report kind of a call of an export that ran out of fuel, with the fuel it had.
+pub(crate) const REPORT_OUT_OF_FUEL: u8 = b'x';
+/// Report kind of a `memory.grow` past the memory limit, with the pages it asked for.
+pub(crate) const REPORT_MEMORY_LIMIT: u8 = b'l';
+
+/// What to add to a module.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Instrumentation {
+    /// Count how often every segment of code runs, see `CodeMap`
+    pub(crate) coverage: bool,
+    /// Report the instructions executed and the memory used by every call of an export
+    pub(crate) meter: Option<Meter>,
+    /// Report when every call of an export starts and what it returns, to tell calls
+    /// that trapped
+    pub(crate) calls: bool,
+}
+
+impl Instrumentation {
+    pub(crate) fn is_empty(&self) -> bool {
+        !self.coverage && self.meter.is_none() && !self.calls
+    }
+
+    /// Whether the module must be instrumented, rather than only reporting more if it is.
+    pub(crate) fn enforced(&self) -> bool {
+        self.coverage
+            || self.calls
+            || self
+                .meter
+                .is_some_and(|m| m.max_fuel.is_some() || m.max_pages.is_some())
+    }
+}
+
+/// Limits enforced by a metered module, which traps once it hits one.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Meter {
+    /// Instructions every call of an export may execute
+    pub(crate) max_fuel: Option<u64>,
+    /// Pages the linear memory may grow to
+    pub(crate) max_pages: Option<u64>,
+}
+
+/// The code of a module, split into segments: straight runs of instructions, which run as
+/// often as their first one unless a call in them traps. Offsets are relative to the start
+/// of the code section, like the addresses of DWARF line tables.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct CodeMap {
+    /// Functions defined by the module, by their index in it
+    pub(crate) functions: BTreeMap<u32, FunctionCode>,
+    /// Every segment of every function, in order, by their index in the reports
+    pub(crate) segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FunctionCode {
+    /// Name from the `name` section, or `func[<index>]` without one
+    pub(crate) name: String,
+    /// Offset of the first instruction
+    pub(crate) start: u32,
+    /// Index of the first segment, which runs once per call
+    pub(crate) entry: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Segment {
+    /// Index of the function the segment is in
+    pub(crate) function: u32,
+    /// Offsets of its instructions
+    pub(crate) code: Range<u32>,
+}
+
+/// An instrumented module.
+pub(crate) struct Instrumented {
+    pub(crate) module: Vec<u8>,
+    /// Code of the original module, whose segments are reported with coverage
+    pub(crate) code: CodeMap,
+    /// Pages the linear memory of the module starts with
+    pub(crate) initial_pages: u64,
+}
+
+fn invalid(what: &str) -> MoosyncError {
+    format!("Cannot instrument the wasm module: {}", what).into()
+}
+
+fn parse_error(e: wasmparser::BinaryReaderError) -> MoosyncError {
+    invalid(&e.to_string())
+}
+
+fn reencode_error(e: ReencodeError) -> MoosyncError {
+    match e {
+        ReencodeError::ParseError(e) => parse_error(e),
+        e => invalid(&e.to_string()),
+    }
+}
+
+type Reencoded<T> = std::result::Result<T, ReencodeError>;
+
+/// Whether the instructions after this one may run a different number of times.
+fn ends_segment(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::Block { .. }
+            | Operator::Loop { .. }
+            | Operator::If { .. }
+            | Operator::Else
+            | Operator::End
+            | Operator::Br { .. }
+            | Operator::BrIf { .. }
+            | Operator::BrTable { .. }
+            | Operator::Return
+            | Operator::Call { .. }
+            | Operator::ReturnCall { .. }
+            | Operator::CallIndirect { .. }
+            | Operator::ReturnCallIndirect { .. }
+            | Operator::CallRef { .. }
+            | Operator::ReturnCallRef { .. }
+            | Operator::Try { .. }
+            | Operator::Catch { .. }
+            | Operator::CatchAll
+            | Operator::Delegate { .. }
+            | Operator::Throw { .. }
+            | Operator::Rethrow { .. }
+            | Operator::ThrowRef
+            | Operator::TryTable { .. }
+            | Operator::BrOnNull { .. }
+            | Operator::BrOnNonNull { .. }
+            | Operator::BrOnCast { .. }
+            | Operator::BrOnCastFail { .. }
+    )
+}
+
+/// Reads the instructions of a body, with their offset in the module.
+fn operators<'a>(body: &FunctionBody<'a>) -> wasmparser::Result<Vec<(Operator<'a>, u64)>> {
+    body.get_operators_reader()?
+        .into_iter_with_offsets()
+        .collect()
+}
+
+/// Positions of the first instruction of every segment of a body.
+fn segment_starts(ops: &[(Operator, u64)]) -> Vec<usize> {
+    let mut starts = vec![0];
+    for (i, (op, _)) in ops.iter().enumerate() {
+        if ends_segment(op) && i + 1 < ops.len() {
+            starts.push(i + 1);
+        }
+    }
+    starts
+}
+
+/// What the rewrite needs to know about a module before it starts.
+#[derive(Default)]
+struct Layout {
+    types: u32,
+    /// Function types, by type index
+    func_types: BTreeMap<u32, wasmparser::FuncType>,
+    /// Type of every function, imports first
+    functions: Vec<u32>,
+    imported_funcs: u32,
+    globals: u32,
+    /// The first memory, and whether it is imported
+    memory: Option<(MemoryType, bool)>,
+    /// Exported functions, each once
+    exported: Vec<u32>,
+    code: CodeMap,
+}
+
+impl Layout {
+    fn scan(module: &[u8]) -> Result<Self> {
+        let mut layout = Layout::default();
+        let mut names = BTreeMap::new();
+        let mut code_start = 0;
+        for payload in Parser::new(0).parse_all(module) {
+            match payload.map_err(parse_error)? {
+                Payload::Version {
+                    encoding: Encoding::Component,
+                    ..
+                } => return Err(invalid("components are not supported")),
+                Payload::TypeSection(reader) => {
+                    for group in reader {
+                        for ty in group.map_err(parse_error)?.into_types() {
+                            if let CompositeInnerType::Func(func) = ty.composite_type.inner {
+                                layout.func_types.insert(layout.types, func);
+                            }
+                            layout.types += 1;
+                        }
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader.into_imports() {
+                        match import.map_err(parse_error)?.ty {
+                            TypeRef::Func(ty) => {
+                                layout.functions.push(ty);
+                                layout.imported_funcs += 1;
+                            }
+                            TypeRef::Global(_) => layout.globals += 1,
+                            TypeRef::Memory(memory) if layout.memory.is_none() => {
+                                layout.memory = Some((memory, true));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::FunctionSection(reader) => {
+                    for ty in reader {
+                        layout.functions.push(ty.map_err(parse_error)?);
+                    }
+                }
+                Payload::MemorySection(reader) => {
+                    for memory in reader {
+                        let memory = memory.map_err(parse_error)?;
+                        layout.memory.get_or_insert((memory, false));
+                    }
+                }
+                Payload::GlobalSection(reader) => layout.globals += reader.count(),
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export.map_err(parse_error)?;
+                        if export.kind == ExternalKind::Func
+                            && !layout.exported.contains(&export.index)
+                        {
+                            layout.exported.push(export.index);
+                        }
+                    }
+                }
+                Payload::CodeSectionStart { range, .. } => code_start = range.start,
+                Payload::CodeSectionEntry(body) => {
+                    let ops = operators(&body).map_err(parse_error)?;
+                    let offset = |i: usize| {
+                        let at = ops.get(i).map_or(body.range().end, |(_, at)| *at);
+                        (at - code_start) as u32
+                    };
+                    let function = layout.imported_funcs + layout.code.functions.len() as u32;
+                    let entry = layout.code.segments.len() as u32;
+                    let starts = segment_starts(&ops);
+                    for (n, start) in starts.iter().enumerate() {
+                        let end = starts.get(n + 1).copied().unwrap_or(ops.len());
+                        layout.code.segments.push(Segment {
+                            function,
+                            code: offset(*start)..offset(end),
+                        });
+                    }
+                    layout.code.functions.insert(
+                        function,
+                        FunctionCode {
+                            name: String::new(),
+                            start: offset(0),
+                            entry,
+                        },
+                    );
+                }
+                Payload::CustomSection(reader) => {
+                    if let KnownCustom::Name(reader) = reader.as_known() {
+                        for name in reader {
+                            if let Name::Function(map) = name.map_err(parse_error)? {
+                                for naming in map {
+                                    let naming = naming.map_err(parse_error)?;
+                                    names.insert(naming.index, naming.name.to_string());
+                                }
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if layout
+            .functions
+            .iter()
+            .any(|ty| !layout.func_types.contains_key(ty))
+        {
+            return Err(invalid("a function has a type that is not a function type"));
+        }
+        for (index, function) in &mut layout.code.functions {
+            function.name = names
+                .remove(index)
+                .unwrap_or_else(|| format!("func[{}]", index));
+        }
+        Ok(layout)
+    }
+
+    fn params(&self, func: u32) -> u32 {
+        self.func_types[&self.functions[func as usize]]
+            .params()
+            .len() as u32
+    }
+}
+
+/// Counts the instructions executed by every call of an export, see `Meter`.
+struct Metering {
+    /// Global with the fuel left to the current call
+    fuel: u32,
+    /// `charge(cost: i64)`, which traps once the fuel runs out
+    charge: u32,
+    budget: i64,
+    max_pages: Option<u64>,
+}
+
+/// Counts how often every segment runs, see `CodeMap`.
+struct Counting {
+    /// Global of the first segment, followed by the globals of the others
+    first: u32,
+    /// `flush()`, which reports and resets every counter that is not zero
+    flush: u32,
+}
+
+/// Rewrites a module while it is reencoded, see `instrument`.
+struct Rewriter<'a> {
+    layout: &'a Layout,
+    token: u32,
+    /// `report(kind: i32, value: i64)`
+    report: u32,
+    metering: Option<Metering>,
+    counting: Option<Counting>,
+    /// Wrapper of every exported function
+    wrappers: BTreeMap<u32, u32>,
+    /// Whether wrappers report how their calls end
+    calls: bool,
+    /// Defined function whose body comes next
+    next_body: u32,
+    /// Sections the additions were written to
+    written: Vec<SectionId>,
+}
+
+impl<'a> Rewriter<'a> {
+    fn new(layout: &'a Layout, token: u32, instrumentation: Instrumentation) -> Self {
+        // Added functions come after the functions of the module, which come after the
+        // added imports
+        let mut next_func = layout.functions.len() as u32 + KERNEL_IMPORTS.len() as u32;
+        let mut add_func = || {
+            next_func += 1;
+            next_func - 1
+        };
+        let mut next_global = layout.globals;
+
+        let report = add_func();
+        let metering = instrumentation.meter.map(|meter| {
+            next_global += 1;
+            Metering {
+                fuel: next_global - 1,
+                charge: add_func(),
+                budget: meter
+                    .max_fuel
+                    .map_or(i64::MAX, |fuel| fuel.min(i64::MAX as u64) as i64),
+                max_pages: meter.max_pages,
+            }
+        });
+        let counting = instrumentation.coverage.then(|| Counting {
+            first: next_global,
+            flush: add_func(),
+        });
+        let wrappers = layout
+            .exported
+            .iter()
+            .map(|func| (*func, add_func()))
+            .collect();
+
+        Self {
+            layout,
+            token,
+            report,
+            metering,
+            counting,
+            wrappers,
+            calls: instrumentation.calls,
+            next_body: 0,
+            written: Vec::new(),
+        }
+    }
+
+    /// Index of a function of the module, which every added import moved up.
+    fn remap(&self, func: u32) -> u32 {
+        if func < self.layout.imported_funcs {
+            func
+        } else {
+            func + KERNEL_IMPORTS.len() as u32
+        }
+    }
+
+    fn add_types(&self, types: &mut TypeSection) {
+        for (params, results) in ADDED_TYPES {
+            types
+                .ty()
+                .function(params.iter().copied(), results.iter().copied());
+        }
+    }
+
+    fn add_imports(&self, imports: &mut ImportSection) {
+        for (name, ty) in KERNEL_IMPORTS {
+            imports.import(
+                KERNEL_MODULE,
+                name,
+                EntityType::Function(self.layout.types + ty),
+            );
+        }
+    }
+
+    fn add_functions(&self, functions: &mut FunctionSection) {
+        functions.function(self.layout.types + REPORT_TYPE);
+        if self.metering.is_some() {
+            functions.function(self.layout.types + CHARGE_TYPE);
+        }
+        if self.counting.is_some() {
+            functions.function(self.layout.types + FLUSH_TYPE);
+        }
+        for func in self.wrappers.keys() {
+            functions.function(self.layout.functions[*func as usize]);
+        }
+    }
+
+    fn add_globals(&self, globals: &mut GlobalSection) {
+        let ty = GlobalType {
+            val_type: ValType::I64,
+            mutable: true,
+            shared: false,
+        };
+        if let Some(metering) = &self.metering {
+            globals.global(ty, &ConstExpr::i64_const(metering.budget));
+        }
+        if self.counting.is_some() {
+            for _ in &self.layout.code.segments {
+                globals.global(ty, &ConstExpr::i64_const(0));
+            }
+        }
+    }
+
+    fn add_bodies(&self, code: &mut CodeSection) {
+        code.function(&self.report_body());
+        if let Some(metering) = &self.metering {
+            code.function(&self.charge_body(metering));
+        }
+        if let Some(counting) = &self.counting {
+            code.function(&self.flush_body(counting));
+        }
+        for func in self.wrappers.keys() {
+            code.function(&self.wrapper_body(*func));
+        }
+    }
+
+    /// Calls `report` with `kind` and the value pushed by `value`.
+    fn report(
+        &self,
+        sink: &mut InstructionSink,
+        kind: u8,
+        value: impl FnOnce(&mut InstructionSink),
+    ) {
+        sink.i32_const(kind as i32);
+        value(sink);
+        sink.call(self.report);
+    }
+
+    /// `report`, which logs `<PROBE_PREFIX><token>:<kind><value as 16 hex digits>`.
+    fn report_body(&self) -> Function {
+        let [alloc, store_u8, log_info, free] =
+            [0, 1, 2, 3].map(|import| self.layout.imported_funcs + import);
+        let head = format!("{}{:08x}:", PROBE_PREFIX, self.token);
+        let len = head.len() + 1 + 16;
+        // Parameters: kind, value. Locals: offset of the message, current digit
+        let (kind, value, offset, digit) = (0, 1, 2, 3);
+
+        let mut function = Function::new([(1, ValType::I64), (1, ValType::I32)]);
+        let sink = &mut function.instructions();
+        sink.i64_const(len as i64).call(alloc).local_set(offset);
+        let store = |sink: &mut InstructionSink, at: usize, byte: &dyn Fn(&mut InstructionSink)| {
+            sink.local_get(offset).i64_const(at as i64).i64_add();
+            byte(sink);
+            sink.call(store_u8);
+        };
+        for (at, byte) in head.bytes().enumerate() {
+            store(sink, at, &|sink| {
+                sink.i32_const(byte as i32);
+            });
+        }
+        store(sink, head.len(), &|sink| {
+            sink.local_get(kind);
+        });
+        for i in 0..16 {
+            sink.local_get(value)
+                .i64_const(60 - 4 * i as i64)
+                .i64_shr_u()
+                .i64_const(0xF)
+                .i64_and()
+                .i32_wrap_i64()
+                .local_set(digit);
+            store(sink, head.len() + 1 + i, &|sink| {
+                sink.local_get(digit)
+                    .i32_const(b'0' as i32)
+                    .i32_add()
+                    .local_get(digit)
+                    .i32_const(b'a' as i32 - 10)
+                    .i32_add()
+                    .local_get(digit)
+                    .i32_const(10)
+                    .i32_lt_u()
+                    .select();
+            });
+        }
+        sink.local_get(offset)
+            .call(log_info)
+            .local_get(offset)
+            .call(free)
+            .end();
+        function
+    }
+
+    fn charge_body(&self, metering: &Metering) -> Function {
+        let mut function = Function::new([]);
+        let sink = &mut function.instructions();
+        sink.global_get(metering.fuel)
+            .local_get(0)
+            .i64_sub()
+            .global_set(metering.fuel)
+            .global_get(metering.fuel)
+            .i64_const(0)
+            .i64_lt_s()
+            .if_(BlockType::Empty);
+        self.report(sink, REPORT_OUT_OF_FUEL, |sink| {
+            sink.i64_const(metering.budget);
+        });
+        sink.unreachable().end().end();
+        function
+    }
+
+    fn flush_body(&self, counting: &Counting) -> Function {
+        const MAX_COUNT: i64 = u32::MAX as i64;
+        let mut function = Function::new([]);
+        let sink = &mut function.instructions();
+        for segment in 0..self.layout.code.segments.len() as u32 {
+            let counter = counting.first + segment;
+            sink.global_get(counter)
+                .i64_const(0)
+                .i64_ne()
+                .if_(BlockType::Empty);
+            self.report(sink, REPORT_COUNT, |sink| {
+                sink.i64_const((segment as i64) << 32)
+                    .global_get(counter)
+                    .i64_const(MAX_COUNT)
+                    .global_get(counter)
+                    .i64_const(MAX_COUNT)
+                    .i64_lt_u()
+                    .select()
+                    .i64_or();
+            });
+            sink.i64_const(0).global_set(counter).end();
+        }
+        sink.end();
+        function
+    }
+
+    /// Calls an exported function in place of it. With metering, the call gets the whole
+    /// budget and reports the fuel it used and the size of the memory after it. With
+    /// coverage, the counts are reported before and after it, so the counts of a call
+    /// that trapped are reported by the next one. With `calls`, it reports the call and
+    /// what it returned.
+    fn wrapper_body(&self, func: u32) -> Function {
+        let params = self.layout.params(func);
+        // Local with the result of an export that returns an `i32`, like those of extism
+        let result = (self.calls
+            && self.layout.func_types[&self.layout.functions[func as usize]].results()
+                == [wasmparser::ValType::I32])
+        .then_some(params);
+        let mut function = Function::new(result.map(|_| (1, ValType::I32)));
+        let sink = &mut function.instructions();
+        if self.calls {
+            self.report(sink, REPORT_CALL, |sink| {
+                sink.i64_const(func as i64);
+            });
+        }
+        if let Some(metering) = &self.metering {
+            sink.i64_const(metering.budget).global_set(metering.fuel);
+        }
+        if let Some(counting) = &self.counting {
+            sink.call(counting.flush);
+        }
+        for param in 0..params {
+            sink.local_get(param);
+        }
+        sink.call(self.remap(func));
+        if let Some(result) = result {
+            sink.local_set(result);
+        }
+        if let Some(counting) = &self.counting {
+            sink.call(counting.flush);
+        }
+        if let Some(metering) = &self.metering {
+            self.report(sink, REPORT_FUEL, |sink| {
+                sink.i64_const(metering.budget)
+                    .global_get(metering.fuel)
+                    .i64_sub();
+            });
+            if self.layout.memory.is_some() {
+                self.report(sink, REPORT_MEMORY, |sink| {
+                    sink.memory_size(0).i64_extend_i32_u();
+                });
+            }
+        }
+        if self.calls {
+            self.report(sink, REPORT_RETURN, |sink| match result {
+                Some(result) => {
+                    sink.local_get(result).i64_extend_i32_u();
+                }
+                None => {
+                    sink.i64_const(0);
+                }
+            });
+        }
+        if let Some(result) = result {
+            sink.local_get(result);
+        }
+        sink.end();
+        function
+    }
+
+    /// Copies a body, charging and counting every segment when it starts, and stopping a
+    /// `memory.grow` that asks for more pages than the memory limit. Other failures of
+    /// `memory.grow` are left to the module, which sees -1 as usual.
+    fn rewrite_body(&mut self, code: &mut CodeSection, body: FunctionBody) -> Reencoded<()> {
+        let func = self.layout.imported_funcs + self.next_body;
+        self.next_body += 1;
+        let ops = operators(&body)?;
+        let starts = segment_starts(&ops);
+        let len = ops.len();
+
+        let mut locals = Vec::new();
+        let mut declared = 0;
+        for local in body.get_locals_reader()? {
+            let (count, ty) = local?;
+            declared += count;
+            locals.push((count, self.val_type(ty)?));
+        }
+        // Local with the pages asked for by a guarded `memory.grow`
+        let guard = self
+            .metering
+            .as_ref()
+            .and_then(|metering| metering.max_pages)
+            .filter(|_| {
+                ops.iter()
+                    .any(|(op, _)| matches!(op, Operator::MemoryGrow { mem: 0 }))
+            })
+            .map(|max_pages| {
+                locals.push((1, ValType::I32));
+                (self.layout.params(func) + declared, max_pages)
+            });
+
+        let entry = self.layout.code.functions[&func].entry;
+        let mut function = Function::new(locals);
+        let mut segments = starts.iter().enumerate().peekable();
+        for (i, (op, _)) in ops.into_iter().enumerate() {
+            if let Some((n, start)) = segments.next_if(|(_, start)| **start == i) {
+                let end = starts.get(n + 1).copied().unwrap_or(len);
+                self.enter_segment(&mut function, (end - start) as i64, entry + n as u32);
+            }
+            match (guard, &op) {
+                (Some((delta, max_pages)), Operator::MemoryGrow { mem: 0 }) => {
+                    let asked = |sink: &mut InstructionSink| {
+                        sink.memory_size(0)
+                            .i64_extend_i32_u()
+                            .local_get(delta)
+                            .i64_extend_i32_u()
+                            .i64_add();
+                    };
+                    let sink = &mut function.instructions();
+                    sink.local_set(delta);
+                    asked(sink);
+                    sink.i64_const(max_pages.min(i64::MAX as u64) as i64)
+                        .i64_gt_u()
+                        .if_(BlockType::Empty);
+                    self.report(sink, REPORT_MEMORY_LIMIT, asked);
+                    sink.unreachable().end().local_get(delta).memory_grow(0);
+                }
+                _ => {
+                    function.instruction(&self.instruction(op)?);
+                }
+            }
+        }
+        code.function(&function);
+        Ok(())
+    }
+
+    fn enter_segment(&self, function: &mut Function, cost: i64, segment: u32) {
+        let sink = &mut function.instructions();
+        if let Some(metering) = &self.metering {
+            sink.i64_const(cost).call(metering.charge);
+        }
+        if let Some(counting) = &self.counting {
+            let counter = counting.first + segment;
+            sink.global_get(counter)
+                .i64_const(1)
+                .i64_add()
+                .global_set(counter);
+        }
+    }
+}
+
+impl Reencode for Rewriter<'_> {
+    type Error = Infallible;
+
+    fn function_index(&mut self, func: u32) -> Reencoded<u32> {
+        Ok(self.remap(func))
+    }
+
+    fn parse_type_section(
+        &mut self,
+        types: &mut TypeSection,
+        section: wasmparser::TypeSectionReader<'_>,
+    ) -> Reencoded<()> {
+        utils::parse_type_section(self, types, section)?;
+        self.add_types(types);
+        self.written.push(SectionId::Type);
+        Ok(())
+    }
+
+    fn parse_import_section(
+        &mut self,
+        imports: &mut ImportSection,
+        section: wasmparser::ImportSectionReader<'_>,
+    ) -> Reencoded<()> {
+        utils::parse_import_section(self, imports, section)?;
+        self.add_imports(imports);
+        self.written.push(SectionId::Import);
+        Ok(())
+    }
+
+    fn parse_function_section(
+        &mut self,
+        functions: &mut FunctionSection,
+        section: wasmparser::FunctionSectionReader<'_>,
+    ) -> Reencoded<()> {
+        utils::parse_function_section(self, functions, section)?;
+        self.add_functions(functions);
+        self.written.push(SectionId::Function);
+        Ok(())
+    }
+
+    /// Lowers the maximum of the memory to the limit, which `instrument` checked is not
+    /// below its minimum.
+    fn parse_memory_section(
+        &mut self,
+        memories: &mut MemorySection,
+        section: wasmparser::MemorySectionReader<'_>,
+    ) -> Reencoded<()> {
+        let max_pages = self.metering.as_ref().and_then(|m| m.max_pages);
+        for (i, memory) in section.into_iter().enumerate() {
+            let mut memory = self.memory_type(memory?)?;
+            if let (0, Some(max_pages)) = (i, max_pages) {
+                memory.maximum = Some(memory.maximum.map_or(max_pages, |max| max.min(max_pages)));
+            }
+            memories.memory(memory);
+        }
+        Ok(())
+    }
+
+    fn parse_global_section(
+        &mut self,
+        globals: &mut GlobalSection,
+        section: wasmparser::GlobalSectionReader<'_>,
+    ) -> Reencoded<()> {
+        utils::parse_global_section(self, globals, section)?;
+        self.add_globals(globals);
+        self.written.push(SectionId::Global);
+        Ok(())
+    }
+
+    /// Points every exported function at its wrapper.
+    fn parse_export(
+        &mut self,
+        exports: &mut ExportSection,
+        export: wasmparser::Export<'_>,
+    ) -> Reencoded<()> {
+        match self.wrappers.get(&export.index) {
+            Some(wrapper) if export.kind == ExternalKind::Func => {
+                exports.export(export.name, ExportKind::Func, *wrapper);
+                Ok(())
+            }
+            _ => utils::parse_export(self, exports, export),
+        }
+    }
+
+    fn parse_code_section(
+        &mut self,
+        code: &mut CodeSection,
+        section: wasmparser::CodeSectionReader<'_>,
+    ) -> Reencoded<()> {
+        for body in section {
+            self.rewrite_body(code, body?)?;
+        }
+        self.add_bodies(code);
+        self.written.push(SectionId::Code);
+        Ok(())
+    }
+
+    /// Drops the DWARF sections, whose addresses no longer match the code.
+    fn parse_custom_section(
+        &mut self,
+        module: &mut Module,
+        section: wasmparser::CustomSectionReader<'_>,
+    ) -> Reencoded<()> {
+        if section.name().starts_with(".debug_") {
+            return Ok(());
+        }
+        utils::parse_custom_section(self, module, section)
+    }
+
+    /// Writes the additions to sections the module does not have, before the first
+    /// section that must come after them.
+    fn intersperse_section_hook(
+        &mut self,
+        module: &mut Module,
+        _after: Option<SectionId>,
+        before: Option<SectionId>,
+    ) -> Reencoded<()> {
+        let position = |id| SECTION_ORDER.iter().position(|s| *s == id);
+        for id in [
+            SectionId::Type,
+            SectionId::Import,
+            SectionId::Function,
+            SectionId::Global,
+            SectionId::Code,
+        ] {
+            if self.written.contains(&id)
+                || before.is_some_and(|before| position(before) <= position(id))
+            {
+                continue;
+            }
+            match id {
+                SectionId::Type => {
+                    let mut types = TypeSection::new();
+                    self.add_types(&mut types);
+                    module.section(&types);
+                }
+                SectionId::Import => {
+                    let mut imports = ImportSection::new();
+                    self.add_imports(&mut imports);
+                    module.section(&imports);
+                }
+                SectionId::Function => {
+                    let mut functions = FunctionSection::new();
+                    self.add_functions(&mut functions);
+                    module.section(&functions);
+                }
+                SectionId::Global => {
+                    let mut globals = GlobalSection::new();
+                    self.add_globals(&mut globals);
+                    module.section(&globals);
+                }
+                _ => {
+                    let mut code = CodeSection::new();
+                    self.add_bodies(&mut code);
+                    module.section(&code);
+                }
+            }
+            self.written.push(id);
+        }
+        Ok(())
+    }
+}
+
+/// Rewrites a module. Every report carries `token`, so the reports of modules loaded
+/// at the same time can be told apart.
+pub(crate) fn instrument(
+    module: &[u8],
+    token: u32,
+    instrumentation: Instrumentation,
+) -> Result<Instrumented> {
+    let layout = Layout::scan(module)?;
+    if let Some(max_pages) = instrumentation.meter.and_then(|m| m.max_pages) {
+        match layout.memory {
+            Some((_, true)) => {
+                return Err(invalid(
+                    "the module imports its memory, which cannot be limited",
+                ));
+            }
+            Some((memory, _)) if memory.memory64 || memory.page_size_log2.is_some() => {
+                return Err(invalid(
+                    "the memory is 64-bit or has custom pages, which cannot be limited",
+                ));
+            }
+            Some((memory, _)) if memory.initial > max_pages => {
+                return Err(invalid(&format!(
+                    "the memory limit of {} pages ({}MB) is below the {} pages ({}MB) the \
+                     module starts with",
+                    max_pages,
+                    pages_to_mb(max_pages),
+                    memory.initial,
+                    pages_to_mb(memory.initial)
+                )));
+            }
+            _ => {}
+        }
+    }
+
+    let mut rewritten = Module::new();
+    Rewriter::new(&layout, token, instrumentation)
+        .parse_core_module(&mut rewritten, Parser::new(0), module)
+        .map_err(reencode_error)?;
+
+    Ok(Instrumented {
+        module: rewritten.finish(),
+        initial_pages: layout.memory.map_or(0, |(memory, _)| memory.initial),
+        code: layout.code,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmparser::{Export, Validator};
+
+    use super::*;
+
+    const EXTENSION: &[u8] = include_bytes!("../tests/fixtures/extension/extension.wasm");
+
+    const LIMITED: Instrumentation = Instrumentation {
+        coverage: true,
+        meter: Some(Meter {
+            max_fuel: Some(1_000_000),
+            max_pages: Some(64),
+        }),
+        calls: true,
+    };
+
+    fn validate(module: &[u8]) {
+        Validator::new().validate_all(module).unwrap();
+    }
+
+    fn bodies(module: &[u8]) -> Vec<Vec<Operator<'_>>> {
+        Parser::new(0)
+            .parse_all(module)
+            .filter_map(|payload| match payload.unwrap() {
+                Payload::CodeSectionEntry(body) => Some(
+                    operators(&body)
+                        .unwrap()
+                        .into_iter()
+                        .map(|(op, _)| op)
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn exports(module: &[u8]) -> Vec<Export<'_>> {
+        Parser::new(0)
+            .parse_all(module)
+            .filter_map(|payload| match payload.unwrap() {
+                Payload::ExportSection(reader) => Some(reader),
+                _ => None,
+            })
+            .flatten()
+            .map(|export| export.unwrap())
+            .collect()
+    }
+
+    fn custom_sections(module: &[u8]) -> Vec<&str> {
+        Parser::new(0)
+            .parse_all(module)
+            .filter_map(|payload| match payload.unwrap() {
+                Payload::CustomSection(reader) => Some(reader.name()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_every_body_without_instrumentation() {
+        let instrumented = instrument(EXTENSION, 1, Instrumentation::default()).unwrap();
+        validate(&instrumented.module);
+
+        let imported = Layout::scan(EXTENSION).unwrap().imported_funcs;
+        let remap = |index: u32| {
+            if index < imported {
+                index
+            } else {
+                index + KERNEL_IMPORTS.len() as u32
+            }
+        };
+        let expected = bodies(EXTENSION)
+            .into_iter()
+            .map(|ops| {
+                ops.into_iter()
+                    .map(|op| match op {
+                        Operator::Call { function_index } => Operator::Call {
+                            function_index: remap(function_index),
+                        },
+                        Operator::RefFunc { function_index } => Operator::RefFunc {
+                            function_index: remap(function_index),
+                        },
+                        op => op,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let rewritten = bodies(&instrumented.module);
+        // Followed by the added functions
+        assert_eq!(rewritten[..expected.len()], expected[..]);
+        assert!(rewritten.len() > expected.len());
+    }
+
+    #[test]
+    fn instrumented_modules_validate() {
+        for instrumentation in [
+            Instrumentation {
+                coverage: true,
+                meter: None,
+                calls: false,
+            },
+            Instrumentation {
+                coverage: false,
+                meter: Some(Meter::default()),
+                calls: false,
+            },
+            LIMITED,
+        ] {
+            validate(&instrument(EXTENSION, 7, instrumentation).unwrap().module);
+        }
+    }
+
+    #[test]
+    fn exports_call_wrappers_and_debug_info_is_dropped() {
+        let instrumented = instrument(EXTENSION, 7, LIMITED).unwrap();
+        let layout = Layout::scan(EXTENSION).unwrap();
+        let added = layout.functions.len() as u32 + KERNEL_IMPORTS.len() as u32;
+
+        let original = exports(EXTENSION);
+        let rewritten = exports(&instrumented.module);
+        assert_eq!(
+            original.iter().map(|e| e.name).collect::<Vec<_>>(),
+            rewritten.iter().map(|e| e.name).collect::<Vec<_>>()
+        );
+        for export in rewritten {
+            if export.kind == ExternalKind::Func {
+                assert!(export.index >= added, "{} is not wrapped", export.name);
+            }
+        }
+
+        assert!(
+            custom_sections(EXTENSION)
+                .iter()
+                .any(|name| name.starts_with(".debug_"))
+        );
+        let sections = custom_sections(&instrumented.module);
+        assert!(sections.contains(&"name"));
+        assert!(!sections.iter().any(|name| name.starts_with(".debug_")));
+    }
+
+    #[test]
+    fn names_follow_their_functions() {
+        let instrumented = instrument(EXTENSION, 7, LIMITED).unwrap();
+        let original = Layout::scan(EXTENSION).unwrap();
+        let rewritten = Layout::scan(&instrumented.module).unwrap();
+        for (index, function) in &original.code.functions {
+            let moved = index + KERNEL_IMPORTS.len() as u32;
+            assert_eq!(rewritten.code.functions[&moved].name, function.name);
+        }
+    }
+
+    #[test]
+    fn segments_split_every_body() {
+        let code = instrument(EXTENSION, 7, LIMITED).unwrap().code;
+        assert!(
+            code.functions
+                .values()
+                .any(|function| function.name.contains("greet"))
+        );
+
+        for (index, function) in &code.functions {
+            let segment = &code.segments[function.entry as usize];
+            assert_eq!(segment.function, *index);
+            assert_eq!(segment.code.start, function.start);
+        }
+        for pair in code.segments.windows(2) {
+            assert!(pair[0].code.start < pair[0].code.end);
+            if pair[0].function == pair[1].function {
+                assert_eq!(pair[0].code.end, pair[1].code.start);
+            } else {
+                assert!(pair[0].code.end < pair[1].code.start);
+            }
+        }
+    }
+
+    #[test]
+    fn limits_the_memory() {
+        let memory = |module: &[u8]| {
+            Layout::scan(module)
+                .unwrap()
+                .memory
+                .map(|(memory, _)| memory)
+                .unwrap()
+        };
+        let initial = memory(EXTENSION).initial;
+        let instrumented = instrument(EXTENSION, 7, LIMITED).unwrap();
+        assert_eq!(instrumented.initial_pages, initial);
+        assert_eq!(memory(&instrumented.module).maximum, Some(64));
+
+        let below = Instrumentation {
+            coverage: false,
+            meter: Some(Meter {
+                max_fuel: None,
+                max_pages: Some(initial - 1),
+            }),
+            calls: false,
+        };
+        let error = instrument(EXTENSION, 7, below).err().unwrap().to_string();
+        assert!(error.contains(&format!("limit of {} pages", initial - 1)));
+        assert!(error.contains(&format!("the {} pages", initial)));
+    }
+
+    #[test]
+    fn adds_sections_the_module_lacks() {
+        validate(
+            &instrument(&Module::new().finish(), 7, LIMITED)
+                .unwrap()
+                .module,
+        );
+
+        let mut types = TypeSection::new();
+        types.ty().function([ValType::I32], [ValType::I32]);
+        let mut functions = FunctionSection::new();
+        functions.function(0);
+        let mut exports = ExportSection::new();
+        exports.export("double", ExportKind::Func, 0);
+        let mut code = CodeSection::new();
+        let mut double = Function::new([]);
+        double
+            .instructions()
+            .local_get(0)
+            .local_get(0)
+            .i32_add()
+            .end();
+        code.function(&double);
+        let mut module = Module::new();
+        module
+            .section(&types)
+            .section(&functions)
+            .section(&exports)
+            .section(&code);
+
+        let instrumented = instrument(&module.finish(), 7, LIMITED).unwrap();
+        validate(&instrumented.module);
+        assert_eq!(instrumented.code.segments.len(), 1);
+        assert_eq!(instrumented.initial_pages, 0);
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_instrument() {
+        assert!(instrument(b"not wasm", 7, LIMITED).is_err());
+
+        let mut imports = ImportSection::new();
+        imports.import(
+            "env",
+            "memory",
+            EntityType::Memory(wasm_encoder::MemoryType {
+                minimum: 1,
+                maximum: None,
+                memory64: false,
+                shared: false,
+                page_size_log2: None,
+            }),
+        );
+        let mut module = Module::new();
+        module.section(&imports);
+        let module = module.finish();
+        let error = instrument(&module, 7, LIMITED).err().unwrap();
+        assert!(error.to_string().contains("imports its memory"));
+        assert!(
+            instrument(
+                &module,
+                7,
+                Instrumentation {
+                    coverage: true,
+                    meter: None,
+                    calls: false,
+                }
+            )
+            .is_ok()
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use colored::*;
+use types::errors::Result;
+use walkdir::WalkDir;
+
+use crate::{
+    RunOptions, cli::run_trace_file, collect_trace_files, fixtures::FIXTURES_DIR,
+    format::is_trace_file, manifest::extension_entry,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What the watcher reruns traces from: a single trace file or a directory of traces.
+pub(crate) enum WatchTarget {
+    File(PathBuf),
+    Dir(PathBuf),
+}
+
+impl WatchTarget {
+    fn traces(&self) -> Vec<PathBuf> {
+        match self {
+            WatchTarget::File(file) => vec![file.clone()],
+            WatchTarget::Dir(dir) => collect_trace_files(dir),
+        }
+    }
+
+    fn root(&self) -> &Path {
+        match self {
+            WatchTarget::File(file) => file,
+            WatchTarget::Dir(dir) => dir,
+        }
+    }
+}
+
+fn snapshot(paths: &[&Path]) -> HashMap<PathBuf, SystemTime> {
+    paths
+        .iter()
+        .flat_map(|path| WalkDir::new(path).into_iter().filter_map(|e| e.ok()))
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| {
+            let modified = fs::metadata(e.path()).and_then(|m| m.modified()).ok()?;
+            Some((e.path().to_path_buf(), modified))
+        })
+        .collect()
+}
+
+fn changed_paths(
+    before: &HashMap<PathBuf, SystemTime>,
+    after: &HashMap<PathBuf, SystemTime>,
+) -> HashSet<PathBuf> {
+    let mut changed: HashSet<PathBuf> = after
+        .iter()
+        .filter(|(path, modified)| before.get(*path) != Some(*modified))
+        .map(|(path, _)| path.clone())
+        .collect();
+    changed.extend(before.keys().filter(|p| !after.contains_key(*p)).cloned());
+    changed
+}
+
+/// Whether `path` is inside a fixture directory below `root`. Directories above the
+/// watched root are not considered, so a checkout under a `fixtures` directory still works.
+fn is_fixture(root: &Path, path: &Path) -> bool {
+    path.strip_prefix(root)
+        .is_ok_and(|p| p.components().any(|c| c.as_os_str() == FIXTURES_DIR))
+}
+
+async fn run_traces(traces: &[PathBuf], opts: &RunOptions) {
+    let started = Instant::now();
+    let mut failed = Vec::new();
+
+    for trace in traces {
+        if let Err(e) = run_trace_file(trace, opts).await {
+            println!("{}", e.to_string().red());
+            failed.push(trace);
+        }
+    }
+
+    let passed = traces.len() - failed.len();
+    let status = format!(
+        "{} {}  {} {}  ({:.1}s)",
+        "✓".green(),
+        format!("{} passed", passed).green(),
+        "✗".red(),
+        format!("{} failed", failed.len()).red(),
+        started.elapsed().as_secs_f32()
+    );
+    println!("\n{}", status);
+    for trace in failed {
+        println!("  {} {}", "✗".red(), trace.to_string_lossy().red());
+    }
+    println!("{}", "Watching for changes...".dimmed());
+}
+
+/// Runs the traces once, then reruns them whenever the manifest, the extension's wasm
+/// or the traces change.
+///
+/// A change to the manifest, the wasm or a fixture reruns every trace since any of them
+/// may depend on it. A change to a single trace file only reruns that trace. The wasm path
+/// is read from the manifest again whenever the manifest changes.
+pub(crate) async fn watch(target: WatchTarget, opts: &RunOptions) -> Result<()> {
+    let mut wasm = extension_entry(&opts.manifest_path)?;
+    let mut last = snapshot(&[&opts.manifest_path, &wasm, target.root()]);
+
+    run_traces(&target.traces(), opts).await;
+
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let current = snapshot(&[&opts.manifest_path, &wasm, target.root()]);
+        let mut changed = changed_paths(&last, &current);
+        last = current;
+
+        if changed.contains(&opts.manifest_path) {
+            match extension_entry(&opts.manifest_path) {
+                Ok(entry) if entry != wasm => {
+                    wasm = entry;
+                    last = snapshot(&[&opts.manifest_path, &wasm, target.root()]);
+                    changed.insert(wasm.clone());
+                }
+                Ok(_) => {}
+                Err(e) => println!("{}", e.to_string().red()),
+            }
+        }
+
+        if changed.is_empty() {
+            continue;
+        }
+
+        let rerun_all = changed.iter().any(|path| {
+            path == &opts.manifest_path
+                || path == &wasm
+                || !is_trace_file(path)
+                || is_fixture(target.root(), path)
+        });
+
+        let traces = if rerun_all {
+            target.traces()
+        } else {
+            let mut traces: Vec<PathBuf> = changed.into_iter().filter(|p| p.exists()).collect();
+            traces.sort();
+            traces
+        };
+
+        if traces.is_empty() {
+            continue;
+        }
+
+        println!(
+            "\n{} {}",
+            "Change detected, rerunning".cyan(),
+            if rerun_all {
+                "all traces".to_string()
+            } else {
+                format!("{} trace(s)", traces.len())
+            }
+            .cyan()
+        );
+        run_traces(&traces, opts).await;
+    }
+}
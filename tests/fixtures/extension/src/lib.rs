@@ -0,0 +1,9 @@
+use extism_pdk::*;
+
+#[plugin_fn]
+pub fn greet(name: String) -> FnResult<String> {
+    if name.is_empty() {
+        return Err(WithReturnCode::new(Error::msg("No name given"), 1));
+    }
+    Ok(format!("Hello, {}!", name))
+}